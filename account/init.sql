@@ -5,4 +5,19 @@ create table "users"
     password      varchar(255) not null,
     is_active     boolean not null default true,
     premium_until timestamp
-);
\ No newline at end of file
+);
+
+create table "practice_sessions"
+(
+    id                   serial constraint practice_session_pk primary key,
+    user_id              integer not null references users (id) on delete cascade,
+    room_id              varchar(255) not null,
+    started_at           timestamp not null,
+    duration_ms          bigint not null,
+    speaking_ms          bigint not null,
+    listening_ms         bigint not null,
+    longest_monologue_ms bigint not null,
+    turns                integer not null
+);
+
+create index practice_sessions_user_idx on practice_sessions (user_id, started_at desc);
@@ -1,7 +1,13 @@
-use crate::api::routes::{google_auth, google_auth_callback, login, me, register};
+use crate::api::routes::{
+    google_auth, google_auth_callback, login, me, practice_history, record_practice, register,
+    user_profile,
+};
+use crate::infra::auth::internal::InternalAuth;
 use crate::infra::auth::jwt::JwtManager;
+use crate::infra::repository::practice::PgPracticeRepository;
 use crate::infra::repository::user::PgUserRepository;
 use crate::service::account::AccountService;
+use crate::service::practice::PracticeService;
 use actix_web::web;
 use actix_web::web::ServiceConfig;
 use sqlx::{Pool, Postgres};
@@ -12,19 +18,29 @@ pub fn create_app(
     secret_key: &'static str,
 ) -> Box<dyn Fn(&mut ServiceConfig)> {
     let user_repo: Arc<PgUserRepository> = Arc::new(PgUserRepository::new(pool.clone()));
+    let practice_repo: Arc<PgPracticeRepository> =
+        Arc::new(PgPracticeRepository::new(pool.clone()));
 
     Box::new(move |cfg: &mut ServiceConfig| {
         let jwt_manager = web::Data::new(JwtManager::new(secret_key.to_string()));
 
         let user_repo = Arc::clone(&user_repo);
         let account_service = web::Data::new(AccountService::new(Arc::clone(&user_repo) as Arc<_>));
+        let practice_service =
+            web::Data::new(PracticeService::new(Arc::clone(&practice_repo) as Arc<_>));
+        let internal_auth = web::Data::new(InternalAuth::from_env());
 
         cfg.app_data(jwt_manager)
             .app_data(account_service)
+            .app_data(practice_service)
+            .app_data(internal_auth)
             .service(register)
             .service(login)
             .service(google_auth)
             .service(google_auth_callback)
-            .service(me);
+            .service(me)
+            .service(record_practice)
+            .service(user_profile)
+            .service(practice_history);
     })
 }
@@ -1,6 +1,9 @@
+use crate::domain::model::PracticeSession;
 use crate::infra::auth::g_oauth::create_google_oauth_client;
+use crate::infra::auth::internal::InternalAuth;
 use crate::infra::auth::jwt::JwtManager;
 use crate::service::account::{AccountError, AccountService};
+use crate::service::practice::PracticeService;
 use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, ResponseError};
 use anyhow::anyhow;
 use oauth2::{
@@ -107,6 +110,62 @@ async fn me(
     HttpResponse::Ok().json(user)
 }
 
+// Сервис room присылает статистику разговора после звонка
+#[post("/internal/practice")]
+async fn record_practice(
+    req: HttpRequest,
+    session: web::Json<PracticeSession>,
+    internal_auth: web::Data<InternalAuth>,
+    practice_service: web::Data<PracticeService>,
+) -> Result<impl Responder, AppError> {
+    if !internal_auth.check(&req) {
+        return Ok(HttpResponse::Forbidden().body("forbidden"));
+    }
+
+    let session = practice_service.record(session.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(session))
+}
+
+// Сервис room показывает имена участников комнаты
+#[get("/internal/users/{id}")]
+async fn user_profile(
+    req: HttpRequest,
+    id: web::Path<i64>,
+    internal_auth: web::Data<InternalAuth>,
+    account_service: web::Data<AccountService>,
+) -> Result<impl Responder, AppError> {
+    if !internal_auth.check(&req) {
+        return Ok(HttpResponse::Forbidden().body("forbidden"));
+    }
+
+    match account_service.profile(id.into_inner()).await {
+        Ok(profile) => Ok(HttpResponse::Ok().json(profile)),
+        Err(err) if err.downcast_ref::<AccountError>() == Some(&AccountError::UserNotFound) => {
+            Ok(HttpResponse::NotFound().body("user not found"))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[get("/practice/history")]
+async fn practice_history(
+    req: HttpRequest,
+    jwt_manager: web::Data<JwtManager>,
+    practice_service: web::Data<PracticeService>,
+) -> Result<impl Responder, AppError> {
+    let token = match jwt_manager.extract_claims_from_req(&req) {
+        Ok(token) => token,
+        Err(err) => {
+            return Ok(HttpResponse::BadRequest().body(format!("err: {:?}", err)));
+        }
+    };
+
+    let history = practice_service.history(token.sub).await?;
+
+    Ok(HttpResponse::Ok().json(history))
+}
+
 #[get("/auth/google")]
 async fn google_auth(req: HttpRequest) -> Result<impl Responder, AppError> {
     #[derive(Debug, Deserialize)]
@@ -13,6 +13,13 @@ pub struct User {
     pub premium_until: Option<NaiveDateTime>,
 }
 
+// Публичные данные пользователя, которые видят собеседники
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+pub struct UserProfile {
+    pub id: i32,
+    pub display_name: String,
+}
+
 impl From<PgRow> for User {
     fn from(row: PgRow) -> Self {
         let id: i32 = row.get(0);
@@ -25,3 +32,34 @@ impl From<PgRow> for User {
         }
     }
 }
+
+// Статистика одного разговора пользователя, присылается сервисом room после звонка
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+pub struct PracticeSession {
+    #[serde(default)]
+    pub id: i32,
+    pub user_id: i32,
+    pub room_id: String,
+    pub started_at: NaiveDateTime,
+    pub duration_ms: i64,
+    pub speaking_ms: i64,
+    pub listening_ms: i64,
+    pub longest_monologue_ms: i64,
+    pub turns: i32,
+}
+
+impl From<PgRow> for PracticeSession {
+    fn from(row: PgRow) -> Self {
+        PracticeSession {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            room_id: row.get("room_id"),
+            started_at: row.get("started_at"),
+            duration_ms: row.get("duration_ms"),
+            speaking_ms: row.get("speaking_ms"),
+            listening_ms: row.get("listening_ms"),
+            longest_monologue_ms: row.get("longest_monologue_ms"),
+            turns: row.get("turns"),
+        }
+    }
+}
@@ -11,3 +11,10 @@ pub trait UserRepository: Send + Sync {
     async fn find(&self, id: i64) -> anyhow::Result<Option<User>>;
     async fn find_by_username(&self, username: &str) -> anyhow::Result<Option<User>>;
 }
+
+#[async_trait]
+#[cfg_attr(test, automock)]
+pub trait PracticeRepository: Send + Sync {
+    async fn save(&self, session: &PracticeSession) -> anyhow::Result<PracticeSession>;
+    async fn find_by_user(&self, user_id: i64, limit: i64) -> anyhow::Result<Vec<PracticeSession>>;
+}
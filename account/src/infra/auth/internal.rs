@@ -0,0 +1,31 @@
+use actix_web::HttpRequest;
+use std::env;
+use subtle::ConstantTimeEq;
+
+// Авторизация запросов от других сервисов (room) по общему токену INTERNAL_API_TOKEN.
+// Без токена внутренние ручки отключены.
+pub struct InternalAuth {
+    token: Option<String>,
+}
+
+impl InternalAuth {
+    pub fn from_env() -> InternalAuth {
+        InternalAuth {
+            token: env::var("INTERNAL_API_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+        }
+    }
+
+    pub fn check(&self, req: &HttpRequest) -> bool {
+        let Some(token) = &self.token else {
+            return false;
+        };
+
+        req.headers()
+            .get("Authorization")
+            .and_then(|header| header.to_str().ok())
+            .map(|header| header.trim_start_matches("Bearer").trim())
+            .is_some_and(|header| bool::from(header.as_bytes().ct_eq(token.as_bytes())))
+    }
+}
@@ -1,2 +1,3 @@
 pub mod g_oauth;
+pub mod internal;
 pub mod jwt;
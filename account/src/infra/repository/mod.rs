@@ -1 +1,2 @@
+pub mod practice;
 pub mod user;
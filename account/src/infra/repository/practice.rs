@@ -0,0 +1,49 @@
+use crate::domain::model::PracticeSession;
+use crate::domain::repository;
+use async_trait::async_trait;
+use sqlx::{Pool, Postgres};
+
+#[derive(Clone)]
+pub struct PgPracticeRepository {
+    pub pool: Pool<Postgres>,
+}
+
+impl PgPracticeRepository {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        PgPracticeRepository { pool }
+    }
+}
+
+#[async_trait]
+impl repository::PracticeRepository for PgPracticeRepository {
+    async fn save(&self, session: &PracticeSession) -> anyhow::Result<PracticeSession> {
+        let row = sqlx::query(
+            "INSERT INTO practice_sessions(user_id, room_id, started_at, duration_ms, speaking_ms, \
+             listening_ms, longest_monologue_ms, turns) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING *",
+        )
+        .bind(session.user_id)
+        .bind(&session.room_id)
+        .bind(session.started_at)
+        .bind(session.duration_ms)
+        .bind(session.speaking_ms)
+        .bind(session.listening_ms)
+        .bind(session.longest_monologue_ms)
+        .bind(session.turns)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.into())
+    }
+
+    async fn find_by_user(&self, user_id: i64, limit: i64) -> anyhow::Result<Vec<PracticeSession>> {
+        let rows = sqlx::query(
+            "SELECT * FROM practice_sessions WHERE user_id = $1 ORDER BY started_at DESC LIMIT $2",
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| r.into()).collect())
+    }
+}
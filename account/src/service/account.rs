@@ -1,4 +1,4 @@
-use crate::domain::model::User;
+use crate::domain::model::{User, UserProfile};
 use crate::domain::repository::UserRepository;
 use anyhow::Result;
 use rand::distributions::Alphanumeric;
@@ -59,6 +59,20 @@ impl AccountService {
         }
     }
 
+    // Имя для собеседников. У пользователей из Google username - это email, его не показываем.
+    pub async fn profile(&self, id: i64) -> Result<UserProfile> {
+        let user = self.me(id).await?;
+        let display_name = match user.username.split_once('@') {
+            Some((name, _)) => name.to_string(),
+            None => user.username,
+        };
+
+        Ok(UserProfile {
+            id: user.id,
+            display_name,
+        })
+    }
+
     pub async fn me(&self, id: i64) -> Result<User> {
         self.user_repo
             .find(id)
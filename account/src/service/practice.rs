@@ -0,0 +1,32 @@
+use crate::domain::model::PracticeSession;
+use crate::domain::repository::PracticeRepository;
+use anyhow::Result;
+use std::sync::Arc;
+
+const HISTORY_LIMIT: i64 = 50;
+
+pub struct PracticeService {
+    practice_repo: Arc<dyn PracticeRepository>,
+}
+
+impl PracticeService {
+    pub fn new(practice_repo: Arc<dyn PracticeRepository>) -> PracticeService {
+        PracticeService { practice_repo }
+    }
+
+    pub async fn record(&self, session: PracticeSession) -> Result<PracticeSession> {
+        // Пустые сессии (соединились и сразу вышли) в историю не попадают
+        if session.speaking_ms == 0 && session.listening_ms == 0 {
+            return Ok(session);
+        }
+
+        self.practice_repo.save(&session).await
+    }
+
+    // Последние разговоры пользователя, новые первыми
+    pub async fn history(&self, user_id: i64) -> Result<Vec<PracticeSession>> {
+        self.practice_repo
+            .find_by_user(user_id, HISTORY_LIMIT)
+            .await
+    }
+}
@@ -1,8 +1,10 @@
 #[cfg(test)]
 #[allow(clippy::module_inception)]
 mod tests {
-    use crate::domain::repository::MockUserRepository;
+    use crate::domain::model::PracticeSession;
+    use crate::domain::repository::{MockPracticeRepository, MockUserRepository};
     use crate::service::account::{AccountError, AccountService};
+    use crate::service::practice::PracticeService;
     use anyhow::Result;
     use mockall::predicate::eq;
     use std::sync::Arc;
@@ -41,4 +43,69 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_service_profile_hides_email() -> Result<()> {
+        let mut user_repo = MockUserRepository::new();
+        user_repo.expect_find().with(eq(1)).returning(|_| {
+            Box::pin(async {
+                Ok(Some(crate::domain::model::User {
+                    id: 1,
+                    username: "alex@example.com".to_string(),
+                    is_active: true,
+                    ..Default::default()
+                }))
+            })
+        });
+        user_repo
+            .expect_find()
+            .with(eq(2))
+            .returning(|_| Box::pin(async { Ok(None) }));
+
+        let account_service = AccountService::new(Arc::new(user_repo));
+        assert_eq!(account_service.profile(1).await?.display_name, "alex");
+
+        let missing = account_service.profile(2).await.unwrap_err();
+        assert_eq!(
+            missing.downcast::<AccountError>()?,
+            AccountError::UserNotFound
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_service_skip_empty_practice() -> Result<()> {
+        let mut practice_repo = MockPracticeRepository::new();
+        practice_repo.expect_save().times(1).returning(|session| {
+            let session = PracticeSession {
+                id: 1,
+                ..session.clone()
+            };
+            Box::pin(async move { Ok(session) })
+        });
+
+        let practice_service = PracticeService::new(Arc::new(practice_repo));
+
+        let empty = practice_service
+            .record(PracticeSession {
+                user_id: 1,
+                ..Default::default()
+            })
+            .await?;
+        assert_eq!(empty.id, 0);
+
+        let saved = practice_service
+            .record(PracticeSession {
+                user_id: 1,
+                speaking_ms: 60_000,
+                listening_ms: 90_000,
+                turns: 12,
+                ..Default::default()
+            })
+            .await?;
+        assert_eq!(saved.id, 1);
+
+        Ok(())
+    }
 }
@@ -4,8 +4,8 @@ import { EventEmitter } from 'eventemitter3'
 import config from "./config.ts";
 
 interface WebSocketMessage {
-    type: 'sdp' | 'candidate';
-    playground?: RTCSessionDescriptionInit | RTCIceCandidateInit;
+    type: 'session' | 'sdp' | 'candidate';
+    playground?: RTCSessionDescriptionInit | RTCIceCandidateInit | {session_id: string};
 }
 
 interface PromiseWithCallback<T = void> {
@@ -45,6 +45,8 @@ export class RoomService {
     private pc: RTCPeerConnection | undefined;
 
     private ws: WebSocket | undefined;
+    // Сессию выдает сервер первым сообщением websocket, она нужна HTTP запросам сигналинга
+    private sessionId: string | undefined;
     private stream?: MediaStream;
 
     constructor(baseURL: string) {
@@ -59,8 +61,14 @@ export class RoomService {
             const wsUrl = `${config.roomWS}?jwt=${token}`;
             this.ws = new WebSocket(wsUrl);
 
-            const { callback, promise } = createPromiseWithCallback<Event>();
-            this.ws.addEventListener("open", callback);
+            const { callback, promise } = createPromiseWithCallback();
+            this.ws.addEventListener("message", (event) => {
+                const message: WebSocketMessage = JSON.parse(event.data);
+                if (message.type === "session") {
+                    this.sessionId = (message.playground as {session_id: string}).session_id;
+                    callback();
+                }
+            });
 
             this.ws.addEventListener("error", (error) => {
                 console.error("WebSocket error:", error);
@@ -70,6 +78,7 @@ export class RoomService {
             this.ws.addEventListener("close", (event) => {
                 console.log("WebSocket closed:", event.code, event.reason);
                 this.ws = undefined;
+                this.sessionId = undefined;
             });
 
             await promise;
@@ -129,7 +138,8 @@ export class RoomService {
         try {
             const resp = await this.axiosClient.post<{answer: RTCSessionDescription}>("/candidate", {
                 candidate,
-                room_id: room
+                room_id: room,
+                session_id: this.sessionId
             }, {
                 headers: {
                     'Content-Type': 'application/json',
@@ -147,7 +157,8 @@ export class RoomService {
         try {
             const resp = await this.axiosClient.post<{answer: RTCSessionDescription}>("/answer", {
                 answer,
-                room_id
+                room_id,
+                session_id: this.sessionId
             }, {
                 headers: {
                     'Content-Type': 'application/json',
@@ -174,7 +185,8 @@ export class RoomService {
 
             const resp = await this.axiosClient.post<{answer: RTCSessionDescription}>("/offer", {
                 offer,
-                room_id
+                room_id,
+                session_id: this.sessionId
             }, {
                 headers: {
                     'Content-Type': 'application/json',
@@ -278,6 +290,8 @@ export class RoomService {
         }
 
         switch (message.type) {
+            case "session":
+                break;
             case "sdp":
                 await this.handleSdpMessage(message.playground as RTCSessionDescriptionInit, user, room, emitter);
                 break;
@@ -0,0 +1,105 @@
+use crate::webrtc::sfu::{PracticeHistory, UserDirectory};
+use crate::webrtc::speaker::TalkSummary;
+use anyhow::Result;
+use chrono::NaiveDateTime;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+// Сервис account не должен задерживать вход в комнату и сохранение истории надолго
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Клиент сервиса account: сохраняет статистику разговоров в историю практики пользователя
+// и отдает имена пользователей для списка участников комнаты
+pub struct AccountClient {
+    url: String,
+    token: String,
+    http: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct PracticeSessionRequest<'a> {
+    user_id: i32,
+    room_id: &'a str,
+    started_at: NaiveDateTime,
+    duration_ms: u64,
+    speaking_ms: u64,
+    listening_ms: u64,
+    longest_monologue_ms: u64,
+    turns: u32,
+}
+
+#[derive(Deserialize)]
+struct UserProfile {
+    display_name: String,
+}
+
+impl AccountClient {
+    pub fn new(url: String, token: String) -> Self {
+        AccountClient {
+            url: url.trim_end_matches('/').to_string(),
+            token,
+            http: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("Could not build account HTTP client"),
+        }
+    }
+
+    async fn send(&self, summary: TalkSummary) -> Result<()> {
+        let user_id = i32::try_from(summary.user_id)?;
+
+        let request = PracticeSessionRequest {
+            user_id,
+            room_id: &summary.room_id,
+            started_at: summary.started_at,
+            duration_ms: summary.duration_ms,
+            speaking_ms: summary.speaking_ms,
+            listening_ms: summary.listening_ms,
+            longest_monologue_ms: summary.longest_monologue_ms,
+            turns: summary.turns,
+        };
+
+        self.http
+            .post(format!("{}/internal/practice", self.url))
+            .bearer_auth(&self.token)
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn profile(&self, user_id: String) -> Result<Option<String>> {
+        let response = self
+            .http
+            .get(format!("{}/internal/users/{user_id}", self.url))
+            .bearer_auth(&self.token)
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let profile: UserProfile = response.error_for_status()?.json().await?;
+        Ok(Some(profile.display_name))
+    }
+}
+
+impl PracticeHistory for AccountClient {
+    fn save(&self, summary: TalkSummary) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(self.send(summary))
+    }
+}
+
+impl UserDirectory for AccountClient {
+    fn display_name(
+        &self,
+        user_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send + '_>> {
+        Box::pin(self.profile(user_id))
+    }
+}
@@ -0,0 +1,337 @@
+// Модуль cluster распределяет комнаты между узлами room. Комната живет на одном узле,
+// остальные узлы проксируют к нему сигналинг из HTTP и websocket, а также запросы WHIP/WHEP.
+// Узел владеет комнатами, пока продлевает аренду heartbeat'ом, комнаты упавшего узла забирают другие.
+pub mod registry;
+
+use crate::cluster::registry::ClusterRegistry;
+use crate::webrtc::axum::{SignalingRequest, SignalingResponse};
+use crate::webrtc::room::RoomLifecycle;
+use crate::webrtc::sfu::Sfu;
+use crate::webrtc::whip::MediaRequest;
+use anyhow::{bail, Result};
+use http::{HeaderMap, StatusCode};
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::SystemTime;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Duration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub id: String,
+    // Адрес, по которому узел доступен клиентам и другим узлам
+    pub url: String,
+}
+
+pub enum Route {
+    Local,
+    Remote(Node),
+}
+
+// Запрос из websocket, который узел с сокетом передает узлу комнаты
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ForwardedRequest {
+    pub session_id: String,
+    pub user_id: i64,
+    pub request: SignalingRequest,
+}
+
+// Запрос WHIP/WHEP, который принявший его узел передает узлу комнаты
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ForwardedMedia {
+    pub session_id: String,
+    pub room_id: String,
+    pub request: MediaRequest,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ForwardedResponse {
+    pub answer: Option<RTCSessionDescription>,
+}
+
+// Сообщение SFU для websocket, открытого на другом узле
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ForwardedSignal {
+    pub session_id: String,
+    pub response: SignalingResponse,
+}
+
+// Ошибка узла комнаты, клиент получает ее статус как есть
+#[derive(Error, Debug)]
+#[error("node {node} responded with {status}: {message}")]
+pub struct NodeError {
+    node: String,
+    pub status: StatusCode,
+    pub message: String,
+}
+
+pub struct ClusterInner {
+    node: Node,
+    registry: Arc<dyn ClusterRegistry>,
+    // Общий для узлов токен внутренних запросов
+    token: Option<String>,
+    http: reqwest::Client,
+    // Сколько узел без heartbeat считается живым
+    lease: Duration,
+}
+
+pub struct Cluster(Arc<ClusterInner>);
+
+impl Cluster {
+    pub fn new(
+        node: Node,
+        registry: Arc<dyn ClusterRegistry>,
+        token: Option<String>,
+        lease: Duration,
+    ) -> Self {
+        Cluster(Arc::new(ClusterInner {
+            node,
+            registry,
+            token,
+            http: reqwest::Client::new(),
+            lease,
+        }))
+    }
+}
+
+impl Clone for Cluster {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl Deref for Cluster {
+    type Target = ClusterInner;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Cluster {
+    fn route(&self, owner: Option<Node>) -> Route {
+        match owner {
+            Some(owner) if owner.id != self.node.id => Route::Remote(owner),
+            _ => Route::Local,
+        }
+    }
+
+    // Где обслуживается комната. Комната без владельца или с владельцем,
+    // у которого истекла аренда, закрепляется за этим узлом.
+    pub async fn claim(&self, room_id: &str) -> Result<Route> {
+        let mut owner = self
+            .registry
+            .claim(room_id.to_string(), self.node.clone())
+            .await?;
+        if owner.id != self.node.id && !self.is_alive(&owner).await? {
+            warn!(room:? = room_id, node:? = owner.id; "Taking over room from node without heartbeat");
+            self.registry
+                .release(room_id.to_string(), owner.id.clone())
+                .await?;
+            owner = self
+                .registry
+                .claim(room_id.to_string(), self.node.clone())
+                .await?;
+        }
+        Ok(self.route(Some(owner)))
+    }
+
+    // То же без закрепления, для запросов к уже существующим комнатам.
+    // Комнаты упавшего узла больше нет, запрос обслуживается здесь.
+    pub async fn locate(&self, room_id: &str) -> Result<Route> {
+        let owner = match self.registry.owner(room_id.to_string()).await? {
+            Some(owner) if !self.is_alive(&owner).await? => None,
+            owner => owner,
+        };
+        Ok(self.route(owner))
+    }
+
+    async fn is_alive(&self, node: &Node) -> Result<bool> {
+        let Some(at) = self.registry.last_heartbeat(node.id.clone()).await? else {
+            return Ok(false);
+        };
+        // Время из будущего - расхождение часов узлов, такой узел считается живым
+        Ok(SystemTime::now()
+            .duration_since(at)
+            .map_or(true, |age| age < self.lease))
+    }
+
+    // Продлевает аренду комнат узла, пока он работает
+    pub fn spawn_heartbeat(&self) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(this.lease / 3);
+            loop {
+                interval.tick().await;
+                if let Err(e) = this.registry.heartbeat(this.node.id.clone()).await {
+                    warn!(node:? = this.node.id, err:? = e; "Could not renew node lease");
+                }
+            }
+        });
+    }
+
+    // Снимает закрепление закрытых комнат и оставшихся от прошлого запуска узла
+    pub fn spawn_room_release(&self, sfu: &Sfu) {
+        let this = self.clone();
+        let mut lifecycle = sfu.subscribe_lifecycle();
+        tokio::spawn(async move {
+            if let Err(e) = this.registry.release_node(this.node.id.clone()).await {
+                warn!(node:? = this.node.id, err:? = e; "Could not release rooms of previous run");
+            }
+
+            loop {
+                match lifecycle.recv().await {
+                    Ok(RoomLifecycle::Closed { room_id, .. }) => {
+                        if let Err(e) = this
+                            .registry
+                            .release(room_id.clone(), this.node.id.clone())
+                            .await
+                        {
+                            warn!(room:? = room_id, err:? = e; "Could not release room");
+                        }
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    pub async fn register_session(&self, session_id: &str) {
+        if let Err(e) = self
+            .registry
+            .register_session(session_id.to_string(), self.node.clone())
+            .await
+        {
+            warn!(user:? = session_id, err:? = e; "Could not register session in cluster");
+        }
+    }
+
+    pub async fn remove_session(&self, session_id: &str) {
+        if let Err(e) = self
+            .registry
+            .remove_session(session_id.to_string(), self.node.id.clone())
+            .await
+        {
+            warn!(user:? = session_id, err:? = e; "Could not remove session from cluster");
+        }
+    }
+
+    // Другой узел, на котором открыт websocket сессии
+    pub async fn session_node(&self, session_id: &str) -> Result<Option<Node>> {
+        let node = self.registry.session_node(session_id.to_string()).await?;
+        Ok(node.filter(|node| node.id != self.node.id))
+    }
+
+    pub async fn forward_request(
+        &self,
+        node: &Node,
+        request: &ForwardedRequest,
+    ) -> Result<Option<RTCSessionDescription>> {
+        info!(user:? = request.session_id, node:? = node.id; "Forwarding request to room node");
+        let response: ForwardedResponse = self
+            .post(node, "/internal/cluster/request", request)
+            .await?;
+        Ok(response.answer)
+    }
+
+    pub async fn forward_media(
+        &self,
+        node: &Node,
+        request: &ForwardedMedia,
+    ) -> Result<Option<RTCSessionDescription>> {
+        info!(user:? = request.session_id, node:? = node.id; "Forwarding WHIP/WHEP request to room node");
+        let response: ForwardedResponse =
+            self.post(node, "/internal/cluster/media", request).await?;
+        Ok(response.answer)
+    }
+
+    pub async fn forward_signal(&self, node: &Node, signal: &ForwardedSignal) -> Result<()> {
+        self.post::<_, ()>(node, "/internal/cluster/signal", signal)
+            .await
+    }
+
+    async fn post<T: Serialize, R: DeserializeOwned>(
+        &self,
+        node: &Node,
+        path: &str,
+        body: &T,
+    ) -> Result<R> {
+        let Some(token) = &self.token else {
+            bail!("INTERNAL_API_TOKEN is required for cluster requests")
+        };
+
+        let response = self
+            .http
+            .post(format!("{}{path}", node.url.trim_end_matches('/')))
+            .bearer_auth(token)
+            .json(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(NodeError {
+                node: node.id.clone(),
+                status,
+                message,
+            }
+            .into());
+        }
+
+        Ok(response.json().await?)
+    }
+
+    // Внутренние запросы принимаются только с общим токеном узлов
+    pub fn authorize(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.token else {
+            return false;
+        };
+
+        headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| bool::from(value.trim().as_bytes().ct_eq(token.as_bytes())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::registry::MemoryRegistry;
+
+    fn cluster(id: &str, registry: &Arc<MemoryRegistry>, lease: Duration) -> Cluster {
+        let node = Node {
+            id: id.to_string(),
+            url: format!("http://{id}"),
+        };
+        Cluster::new(node, Arc::clone(registry) as _, None, lease)
+    }
+
+    #[tokio::test]
+    async fn take_over_room_without_heartbeat() {
+        let registry = Arc::new(MemoryRegistry::default());
+        let a = cluster("a", &registry, Duration::from_secs(15));
+        assert!(matches!(a.claim("1").await.unwrap(), Route::Local));
+
+        // без heartbeat аренда a истекла
+        let b = cluster("b", &registry, Duration::from_secs(15));
+        assert!(matches!(b.locate("1").await.unwrap(), Route::Local));
+
+        // a продлевает аренду, комната остается за ним
+        registry.heartbeat("a".to_string()).await.unwrap();
+        assert!(matches!(b.claim("1").await.unwrap(), Route::Remote(node) if node.id == "a"));
+
+        // с нулевой арендой последний heartbeat a уже устарел
+        let c = cluster("c", &registry, Duration::ZERO);
+        assert!(matches!(c.claim("1").await.unwrap(), Route::Local));
+        assert!(matches!(b.locate("1").await.unwrap(), Route::Local));
+    }
+}
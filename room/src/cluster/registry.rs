@@ -0,0 +1,424 @@
+use crate::cluster::Node;
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+// Какой узел владеет комнатой и на каком узле открыт websocket пользователя
+pub trait ClusterRegistry: Sync + Send {
+    // Владелец комнаты. Комната без владельца закрепляется за node.
+    fn claim(
+        &self,
+        room_id: String,
+        node: Node,
+    ) -> Pin<Box<dyn Future<Output = Result<Node>> + Send + '_>>;
+    fn owner(
+        &self,
+        room_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Node>>> + Send + '_>>;
+    // Снимает закрепление, только если комната все еще принадлежит node_id
+    fn release(
+        &self,
+        room_id: String,
+        node_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+    fn register_session(
+        &self,
+        session_id: String,
+        node: Node,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+    fn session_node(
+        &self,
+        session_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Node>>> + Send + '_>>;
+    fn remove_session(
+        &self,
+        session_id: String,
+        node_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+    // Забывает все комнаты и сессии узла, оставшиеся от его прошлого запуска
+    fn release_node(
+        &self,
+        node_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+    // Узел продлевает аренду своих комнат, пока работает
+    fn heartbeat(&self, node_id: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+    fn last_heartbeat(
+        &self,
+        node_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<SystemTime>>> + Send + '_>>;
+}
+
+// Реестр одного процесса, для запуска без кластера
+#[derive(Default)]
+pub struct MemoryRegistry {
+    rooms: Mutex<HashMap<String, Node>>,
+    sessions: Mutex<HashMap<String, Node>>,
+    heartbeats: Mutex<HashMap<String, SystemTime>>,
+}
+
+impl ClusterRegistry for MemoryRegistry {
+    fn claim(
+        &self,
+        room_id: String,
+        node: Node,
+    ) -> Pin<Box<dyn Future<Output = Result<Node>> + Send + '_>> {
+        Box::pin(async move {
+            Ok(self
+                .rooms
+                .lock()
+                .await
+                .entry(room_id)
+                .or_insert(node)
+                .clone())
+        })
+    }
+
+    fn owner(
+        &self,
+        room_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Node>>> + Send + '_>> {
+        Box::pin(async move { Ok(self.rooms.lock().await.get(&room_id).cloned()) })
+    }
+
+    fn release(
+        &self,
+        room_id: String,
+        node_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let mut rooms = self.rooms.lock().await;
+            if rooms.get(&room_id).is_some_and(|node| node.id == node_id) {
+                rooms.remove(&room_id);
+            }
+            Ok(())
+        })
+    }
+
+    fn register_session(
+        &self,
+        session_id: String,
+        node: Node,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            self.sessions.lock().await.insert(session_id, node);
+            Ok(())
+        })
+    }
+
+    fn session_node(
+        &self,
+        session_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Node>>> + Send + '_>> {
+        Box::pin(async move { Ok(self.sessions.lock().await.get(&session_id).cloned()) })
+    }
+
+    fn remove_session(
+        &self,
+        session_id: String,
+        node_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let mut sessions = self.sessions.lock().await;
+            if sessions
+                .get(&session_id)
+                .is_some_and(|node| node.id == node_id)
+            {
+                sessions.remove(&session_id);
+            }
+            Ok(())
+        })
+    }
+
+    fn release_node(
+        &self,
+        node_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            self.rooms.lock().await.retain(|_, node| node.id != node_id);
+            self.sessions
+                .lock()
+                .await
+                .retain(|_, node| node.id != node_id);
+            Ok(())
+        })
+    }
+
+    fn heartbeat(&self, node_id: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            self.heartbeats
+                .lock()
+                .await
+                .insert(node_id, SystemTime::now());
+            Ok(())
+        })
+    }
+
+    fn last_heartbeat(
+        &self,
+        node_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<SystemTime>>> + Send + '_>> {
+        Box::pin(async move { Ok(self.heartbeats.lock().await.get(&node_id).copied()) })
+    }
+}
+
+// Реестр в общем каталоге, для нескольких узлов на одной машине или общем томе.
+// Каждая комната и сессия - отдельный файл с json узла, heartbeat узла - файл со временем.
+pub struct FileRegistry {
+    rooms: PathBuf,
+    sessions: PathBuf,
+    nodes: PathBuf,
+}
+
+impl FileRegistry {
+    pub fn new(dir: PathBuf) -> Self {
+        FileRegistry {
+            rooms: dir.join("rooms"),
+            sessions: dir.join("sessions"),
+            nodes: dir.join("nodes"),
+        }
+    }
+}
+
+// Имя файла для произвольного id
+fn file_name(key: &str) -> String {
+    key.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' {
+                (b as char).to_string()
+            } else {
+                format!("%{b:02x}")
+            }
+        })
+        .collect()
+}
+
+async fn read_node(path: PathBuf) -> Result<Option<Node>> {
+    read_json(path).await
+}
+
+async fn read_json<T: DeserializeOwned>(path: PathBuf) -> Result<Option<T>> {
+    match fs::read(path).await {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Пишет во временный файл рядом, чтобы читатели не увидели файл наполовину
+async fn write_temp<T: Serialize>(dir: &Path, value: &T) -> Result<PathBuf> {
+    fs::create_dir_all(dir).await?;
+    let tmp = dir.join(format!(".{}.tmp", uuid::Uuid::new_v4().simple()));
+    fs::write(&tmp, serde_json::to_vec(value)?).await?;
+    Ok(tmp)
+}
+
+async fn remove_if_owned(path: PathBuf, node_id: &str) -> Result<()> {
+    if read_node(path.clone())
+        .await?
+        .is_some_and(|node| node.id == node_id)
+    {
+        match fs::remove_file(path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl ClusterRegistry for FileRegistry {
+    fn claim(
+        &self,
+        room_id: String,
+        node: Node,
+    ) -> Pin<Box<dyn Future<Output = Result<Node>> + Send + '_>> {
+        Box::pin(async move {
+            let path = self.rooms.join(file_name(&room_id));
+            if let Some(owner) = read_node(path.clone()).await? {
+                return Ok(owner);
+            }
+
+            // hard link не перезаписывает существующий файл, из двух узлов комнату получит один
+            let tmp = write_temp(&self.rooms, &node).await?;
+            let linked = fs::hard_link(&tmp, &path).await;
+            fs::remove_file(&tmp).await?;
+            match linked {
+                Ok(()) => Ok(node),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    Ok(read_node(path).await?.unwrap_or(node))
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn owner(
+        &self,
+        room_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Node>>> + Send + '_>> {
+        Box::pin(read_node(self.rooms.join(file_name(&room_id))))
+    }
+
+    fn release(
+        &self,
+        room_id: String,
+        node_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(
+            async move { remove_if_owned(self.rooms.join(file_name(&room_id)), &node_id).await },
+        )
+    }
+
+    fn register_session(
+        &self,
+        session_id: String,
+        node: Node,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let tmp = write_temp(&self.sessions, &node).await?;
+            fs::rename(tmp, self.sessions.join(file_name(&session_id))).await?;
+            Ok(())
+        })
+    }
+
+    fn session_node(
+        &self,
+        session_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Node>>> + Send + '_>> {
+        Box::pin(read_node(self.sessions.join(file_name(&session_id))))
+    }
+
+    fn remove_session(
+        &self,
+        session_id: String,
+        node_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            remove_if_owned(self.sessions.join(file_name(&session_id)), &node_id).await
+        })
+    }
+
+    fn release_node(
+        &self,
+        node_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            for dir in [&self.rooms, &self.sessions] {
+                let mut entries = match fs::read_dir(dir).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    if !entry.file_name().to_string_lossy().starts_with('.') {
+                        remove_if_owned(entry.path(), &node_id).await?;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn heartbeat(&self, node_id: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let tmp = write_temp(&self.nodes, &SystemTime::now()).await?;
+            fs::rename(tmp, self.nodes.join(file_name(&node_id))).await?;
+            Ok(())
+        })
+    }
+
+    fn last_heartbeat(
+        &self,
+        node_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<SystemTime>>> + Send + '_>> {
+        Box::pin(read_json(self.nodes.join(file_name(&node_id))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str) -> Node {
+        Node {
+            id: id.to_string(),
+            url: format!("http://{id}"),
+        }
+    }
+
+    async fn check_registry(registry: &dyn ClusterRegistry) {
+        let room = "room/1".to_string();
+        assert_eq!(registry.owner(room.clone()).await.unwrap(), None);
+        assert_eq!(
+            registry.claim(room.clone(), node("a")).await.unwrap(),
+            node("a")
+        );
+        assert_eq!(
+            registry.claim(room.clone(), node("b")).await.unwrap(),
+            node("a")
+        );
+
+        // чужой узел не может снять закрепление
+        registry
+            .release(room.clone(), "b".to_string())
+            .await
+            .unwrap();
+        assert_eq!(registry.owner(room.clone()).await.unwrap(), Some(node("a")));
+        registry
+            .release(room.clone(), "a".to_string())
+            .await
+            .unwrap();
+        assert_eq!(registry.owner(room.clone()).await.unwrap(), None);
+
+        registry
+            .register_session("1".to_string(), node("a"))
+            .await
+            .unwrap();
+        registry
+            .register_session("1".to_string(), node("b"))
+            .await
+            .unwrap();
+        assert_eq!(
+            registry.session_node("1".to_string()).await.unwrap(),
+            Some(node("b"))
+        );
+        registry.release_node("b".to_string()).await.unwrap();
+        assert_eq!(registry.session_node("1".to_string()).await.unwrap(), None);
+
+        assert_eq!(
+            registry.last_heartbeat("a".to_string()).await.unwrap(),
+            None
+        );
+        registry.heartbeat("a".to_string()).await.unwrap();
+        assert!(registry
+            .last_heartbeat("a".to_string())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn memory_registry() {
+        check_registry(&MemoryRegistry::default()).await;
+    }
+
+    #[tokio::test]
+    async fn file_registry() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        check_registry(&FileRegistry::new(dir.clone())).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn escape_file_name() {
+        assert_eq!(file_name("abc-1_2"), "abc-1_2");
+        assert_eq!(file_name("../x"), "%2e%2e%2fx");
+    }
+}
@@ -57,6 +57,59 @@ pub struct Jwt(pub Claims);
 pub struct Claims {
     pub(crate) sub: i64,
     pub(crate) exp: i64,
+    // Роль пользователя, у обычных пользователей отсутствует
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) role: Option<String>,
+}
+
+const ADMIN_ROLE: &str = "admin";
+// Преподаватели и проверяющие, которые смотрят комнаты через WHEP
+const OBSERVER_ROLE: &str = "observer";
+
+// То же, что Jwt, но пропускает только токены с ролью admin
+#[derive(Debug)]
+pub struct AdminJwt(pub Claims);
+
+impl<S> FromRequestParts<S> for AdminJwt
+where
+    SecretKey: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = JWTRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Jwt(claims) = Jwt::from_request_parts(parts, state).await?;
+        require_role(claims, &[ADMIN_ROLE]).map(AdminJwt)
+    }
+}
+
+// То же, что Jwt, но пропускает только наблюдателей и администраторов
+#[derive(Debug)]
+pub struct ObserverJwt(pub Claims);
+
+impl<S> FromRequestParts<S> for ObserverJwt
+where
+    SecretKey: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = JWTRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Jwt(claims) = Jwt::from_request_parts(parts, state).await?;
+        require_role(claims, &[OBSERVER_ROLE, ADMIN_ROLE]).map(ObserverJwt)
+    }
+}
+
+fn require_role(claims: Claims, roles: &[&str]) -> Result<Claims, JWTRejection> {
+    if claims
+        .role
+        .as_deref()
+        .is_some_and(|role| roles.contains(&role))
+    {
+        Ok(claims)
+    } else {
+        Err(JWTRejection::Forbidden)
+    }
 }
 
 pub type SecretKey = &'static DecodingKey;
@@ -117,6 +170,7 @@ fn extract_token(
 pub enum JWTRejection {
     InvalidAuthorizationHeader,
     InvalidSignature,
+    Forbidden,
 }
 
 impl IntoResponse for JWTRejection {
@@ -126,6 +180,7 @@ impl IntoResponse for JWTRejection {
                 (StatusCode::UNAUTHORIZED, "invalid authorization header")
             }
             JWTRejection::InvalidSignature => (StatusCode::UNAUTHORIZED, "invalid signature"),
+            JWTRejection::Forbidden => (StatusCode::FORBIDDEN, "role required"),
         }
         .into_response()
     }
@@ -150,7 +205,11 @@ mod tests {
     }
 
     fn create_token(sub: i64, exp: i64) -> String {
-        let claims = Claims { sub, exp };
+        let claims = Claims {
+            sub,
+            exp,
+            role: None,
+        };
         jsonwebtoken::encode(
             &Header::default(),
             &claims,
@@ -196,6 +255,7 @@ mod tests {
         let claims = Claims {
             sub: 1,
             exp: 9999999999,
+            role: None,
         };
         let token = jsonwebtoken::encode(
             &Header::default(),
@@ -212,6 +272,44 @@ mod tests {
         assert!(matches!(result, Err(JWTRejection::InvalidSignature)));
     }
 
+    #[test]
+    fn admin_role() {
+        let admin = Claims {
+            sub: 1,
+            exp: 9999999999,
+            role: Some("admin".to_string()),
+        };
+        assert!(require_role(admin, &[ADMIN_ROLE]).is_ok());
+
+        let token = create_token(2, 9999999999);
+        let claims = extract_token(&HeaderMap::new(), Some(token), get_decoding_key()).unwrap();
+        assert!(claims.0.role.is_none());
+        assert!(matches!(
+            require_role(claims.0, &[ADMIN_ROLE]),
+            Err(JWTRejection::Forbidden)
+        ));
+    }
+
+    #[test]
+    fn observer_role() {
+        let observer = Claims {
+            sub: 1,
+            exp: 9999999999,
+            role: Some("observer".to_string()),
+        };
+        assert!(matches!(
+            require_role(observer, &[ADMIN_ROLE]),
+            Err(JWTRejection::Forbidden)
+        ));
+
+        let observer = Claims {
+            sub: 1,
+            exp: 9999999999,
+            role: Some("observer".to_string()),
+        };
+        assert!(require_role(observer, &[OBSERVER_ROLE, ADMIN_ROLE]).is_ok());
+    }
+
     #[test]
     fn malformed_header() {
         let mut headers = HeaderMap::new();
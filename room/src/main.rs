@@ -1,9 +1,13 @@
-use clap::Parser;
+use clap::{ArgAction, Parser};
 use env_logger::Builder;
-use log::LevelFilter;
+use log::{info, LevelFilter};
+use std::time::Duration;
 use tower_http::cors::CorsLayer;
 
+mod account;
+mod cluster;
 mod extract;
+mod matchmaking;
 mod webrtc;
 
 #[derive(Parser, Debug)]
@@ -11,6 +15,116 @@ mod webrtc;
 pub struct Args {
     #[arg(short, long, default_value_t = 8082)]
     pub port: u16,
+
+    /// Сколько секунд ждать собеседника в очереди
+    #[arg(long, default_value_t = 60)]
+    pub queue_timeout: u64,
+
+    /// Максимум участников в комнате, по умолчанию без ограничения
+    #[arg(long)]
+    pub room_capacity: Option<usize>,
+
+    /// Сколько секунд хранить комнату, в которую никто не зашел
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub empty_room_ttl: u64,
+
+    /// STUN сервера, можно указать несколько раз
+    #[arg(
+        long = "stun-url",
+        env = "STUN_URLS",
+        value_delimiter = ',',
+        default_values = [
+            "stun:stun.l.google.com:19302",
+            "stun:stun.l.google.com:5349",
+            "stun:stun1.l.google.com:3478",
+            "stun:stun1.l.google.com:5349",
+            "stun:stun2.l.google.com:19302",
+            "stun:stun.sipnet.net:3478",
+            "stun:stun.sipnet.ru:3478",
+            "stun:stun.stunprotocol.org:3478",
+        ]
+    )]
+    pub stun_urls: Vec<String>,
+
+    /// TURN сервера, например turn:turn.example.com:3478?transport=udp
+    #[arg(long = "turn-url", env = "TURN_URLS", value_delimiter = ',')]
+    pub turn_urls: Vec<String>,
+
+    /// Постоянные учетные данные TURN
+    #[arg(long, env = "TURN_USERNAME")]
+    pub turn_username: Option<String>,
+
+    #[arg(long, env = "TURN_CREDENTIAL")]
+    pub turn_credential: Option<String>,
+
+    /// Общий секрет TURN REST API (coturn static-auth-secret), имеет приоритет над постоянными учетными данными
+    #[arg(long, env = "TURN_SECRET")]
+    pub turn_secret: Option<String>,
+
+    /// Время жизни временных учетных данных TURN в секундах
+    #[arg(long, default_value_t = 86400)]
+    pub turn_ttl: u64,
+
+    /// Разрешенные видеокодеки в порядке предпочтения
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values = ["vp8", "vp9", "h264", "av1"]
+    )]
+    pub video_codecs: Vec<webrtc::codec::VideoCodec>,
+
+    /// Opus in-band FEC
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub opus_fec: bool,
+
+    /// Opus DTX (не передавать тишину)
+    #[arg(long)]
+    pub opus_dtx: bool,
+
+    /// Сколько последних сообщений чата получает опоздавший участник, 0 - без истории
+    #[arg(long, default_value_t = 20)]
+    pub chat_history: usize,
+
+    /// Сколько секунд держать место участника, потерявшего соединение
+    #[arg(long, default_value_t = 15)]
+    pub reconnect_window: u64,
+
+    /// Адрес сервиса account для сохранения истории разговоров
+    #[arg(long, env = "ACCOUNT_URL")]
+    pub account_url: Option<String>,
+
+    /// Токен для внутренних запросов к сервису account
+    #[arg(long, env = "INTERNAL_API_TOKEN")]
+    pub internal_api_token: Option<String>,
+
+    /// Каталог для записей комнат, без него запись выключена
+    #[arg(long, env = "RECORDINGS_DIR")]
+    pub recordings_dir: Option<std::path::PathBuf>,
+
+    /// Идентификатор узла в кластере, по умолчанию случайный
+    #[arg(long, env = "NODE_ID")]
+    pub node_id: Option<String>,
+
+    /// Адрес, по которому узел доступен клиентам и другим узлам
+    #[arg(long, env = "NODE_URL")]
+    pub node_url: Option<String>,
+
+    /// Общий каталог реестра комнат кластера, без него узел работает один
+    #[arg(long, env = "CLUSTER_DIR")]
+    pub cluster_dir: Option<std::path::PathBuf>,
+
+    /// Через сколько секунд без heartbeat комнаты узла забирают другие узлы
+    #[arg(long, env = "NODE_LEASE", default_value_t = 15, value_parser = clap::value_parser!(u64).range(1..))]
+    pub node_lease: u64,
+
+    /// Сколько секунд после SIGTERM ждать, пока комнаты опустеют
+    #[arg(long, env = "SHUTDOWN_GRACE", default_value_t = 30)]
+    pub shutdown_grace: u64,
+
+    /// Новая сессия пользователя закрывает прежние (replace) или работает вместе с ними (multiple)
+    #[arg(long, env = "SESSION_POLICY", value_enum, default_value_t = webrtc::session::SessionPolicy::Multiple)]
+    pub session_policy: webrtc::session::SessionPolicy,
 }
 
 
@@ -30,12 +144,44 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Args::parse();
 
-    let webrtc_state = webrtc::axum::create_webrtc_state();
+    let webrtc_state = webrtc::axum::create_webrtc_state(&args)?;
+    let grace = Duration::from_secs(args.shutdown_grace);
+    let shutdown = webrtc::axum::drain(webrtc_state.clone(), grace);
 
     let app = webrtc::axum::create_webrtc_router()
         .with_state(webrtc_state)
         .layer(CorsLayer::permissive()) // TODO
     ;
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", args.port)).await?;
-    Ok(axum::serve(listener, app).await?)
+    Ok(axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            shutdown.await
+        })
+        .await?)
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutdown signal received");
 }
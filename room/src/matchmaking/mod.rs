@@ -0,0 +1,306 @@
+// Модуль matchmaking подбирает случайного собеседника и выдает пару общую комнату
+pub mod queue;
+
+use crate::matchmaking::queue::{Joined, WaitingQueue};
+use crate::webrtc::room::{RoomLifecycle, RoomOptions};
+use crate::webrtc::sfu::{Sfu, Signalling};
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tokio::time::Duration;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MatchmakingEvent {
+    Waiting,
+    Matched { room_id: String, partner_id: i64 },
+    Timeout,
+    Cancelled,
+    PartnerLeft { room_id: String },
+}
+
+struct Match {
+    room_id: String,
+    partner: String,
+}
+
+pub struct MatchmakerInner {
+    queue: Mutex<WaitingQueue>,
+    matches: Mutex<HashMap<String, Match>>,
+    sfu: Sfu,
+    signalling: Arc<dyn Signalling>,
+    queue_timeout: Duration,
+}
+
+pub struct Matchmaker(Arc<MatchmakerInner>);
+
+impl Matchmaker {
+    pub fn new(sfu: Sfu, signalling: Arc<dyn Signalling>, queue_timeout: Duration) -> Self {
+        let matchmaker = Matchmaker(Arc::new(MatchmakerInner {
+            queue: Default::default(),
+            matches: Default::default(),
+            sfu,
+            signalling,
+            queue_timeout,
+        }));
+        matchmaker.spawn_lifecycle_listener();
+
+        matchmaker
+    }
+}
+
+impl Clone for Matchmaker {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl Deref for Matchmaker {
+    type Target = MatchmakerInner;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Matchmaker {
+    pub async fn join(&self, session_id: String, user_id: i64) -> Result<()> {
+        let (joined, waiting) = {
+            let mut queue = self.queue.lock().await;
+            let joined = queue.join(session_id.clone(), user_id);
+            (joined, queue.len())
+        };
+
+        match joined {
+            Joined::Waiting(ticket) => {
+                info!(user:? = session_id, waiting:? = waiting; "Waiting for partner");
+
+                let this = self.clone();
+                let session_id2 = session_id.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(this.queue_timeout).await;
+                    this.expire(session_id2, ticket).await;
+                });
+
+                self.signalling
+                    .send_matchmaking(session_id, MatchmakingEvent::Waiting)
+                    .await
+            }
+            Joined::Paired(partner) => {
+                let room_id = uuid::Uuid::new_v4().to_string();
+                info!(user:? = session_id, partner:? = partner.session_id, room:? = room_id; "Partner found");
+                let options = RoomOptions { capacity: Some(2) };
+                self.sfu.create_room(room_id.clone(), options).await;
+
+                {
+                    let mut matches = self.matches.lock().await;
+                    matches.insert(
+                        session_id.clone(),
+                        Match {
+                            room_id: room_id.clone(),
+                            partner: partner.session_id.clone(),
+                        },
+                    );
+                    matches.insert(
+                        partner.session_id.clone(),
+                        Match {
+                            room_id: room_id.clone(),
+                            partner: session_id.clone(),
+                        },
+                    );
+                }
+
+                let matched = MatchmakingEvent::Matched {
+                    room_id: room_id.clone(),
+                    partner_id: user_id,
+                };
+                if let Err(e) = self
+                    .signalling
+                    .send_matchmaking(partner.session_id.clone(), matched)
+                    .await
+                {
+                    warn!(err:? = e, user:? = partner.session_id; "Could not notify partner");
+                }
+
+                let matched = MatchmakingEvent::Matched {
+                    room_id,
+                    partner_id: partner.user_id,
+                };
+                self.signalling.send_matchmaking(session_id, matched).await
+            }
+        }
+    }
+
+    pub async fn cancel(&self, session_id: String) -> Result<()> {
+        if self.queue.lock().await.cancel(&session_id) {
+            self.signalling
+                .send_matchmaking(session_id, MatchmakingEvent::Cancelled)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // Покинуть текущую комнату и сразу встать в очередь за новым собеседником
+    pub async fn next(&self, session_id: String, user_id: i64) -> Result<()> {
+        self.leave_match(&session_id).await?;
+        self.join(session_id, user_id).await
+    }
+
+    // Websocket закрылся: из очереди сессия уходит сразу, иначе собеседник ждал бы ее напрасно.
+    // Пара сохраняется, пока сессия может вернуться.
+    pub async fn leave_queue(&self, session_id: &str) {
+        self.queue.lock().await.cancel(session_id);
+    }
+
+    // Сессия не вернулась за reconnect_window после закрытия websocket или закрыта заменившей ее
+    pub async fn disconnect(&self, session_id: &str) {
+        self.queue.lock().await.cancel(session_id);
+
+        if let Err(e) = self.leave_match(session_id).await {
+            warn!(err:? = e, user:? = session_id; "Could not leave match");
+        }
+    }
+
+    async fn leave_match(&self, session_id: &str) -> Result<()> {
+        self.end_match(session_id, None).await
+    }
+
+    // room_id - пара распадается, только если сессия все еще в этой комнате:
+    // после next сессия уже может быть в новой паре, а событие пришло о старой комнате
+    async fn end_match(&self, session_id: &str, room_id: Option<&str>) -> Result<()> {
+        let current = {
+            let mut matches = self.matches.lock().await;
+            let current_room = matches.get(session_id).map(|m| m.room_id.as_str());
+            if room_id.is_some_and(|room_id| current_room != Some(room_id)) {
+                return Ok(());
+            }
+            let current = matches.remove(session_id);
+            if let Some(current) = &current {
+                matches.remove(&current.partner);
+            }
+            current
+        };
+
+        let Some(current) = current else {
+            return Ok(());
+        };
+
+        self.sfu.leave(session_id, &current.room_id).await?;
+        // Комнату пары создал matchmaker, сборщик пустых комнат ее не закроет
+        self.sfu.release_room(&current.room_id).await;
+
+        let left = MatchmakingEvent::PartnerLeft {
+            room_id: current.room_id,
+        };
+        if let Err(e) = self
+            .signalling
+            .send_matchmaking(current.partner.clone(), left)
+            .await
+        {
+            warn!(err:? = e, user:? = current.partner; "Could not notify partner");
+        }
+
+        Ok(())
+    }
+
+    // Пара распадается, когда SFU удалил участника, например не восстановившего соединение
+    // за reconnect_window, и забывается, когда ее комната закрылась (оба участника ушли)
+    fn spawn_lifecycle_listener(&self) {
+        let this = self.clone();
+        let mut lifecycle = self.sfu.subscribe_lifecycle();
+        tokio::spawn(async move {
+            loop {
+                match lifecycle.recv().await {
+                    Ok(RoomLifecycle::Left {
+                        room_id,
+                        session_id,
+                        ..
+                    }) => this.on_left(&room_id, &session_id).await,
+                    Ok(RoomLifecycle::Closed { room_id, .. }) => {
+                        this.matches
+                            .lock()
+                            .await
+                            .retain(|_, m| m.room_id != room_id);
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    async fn on_left(&self, room_id: &str, session_id: &str) {
+        if let Err(e) = self.end_match(session_id, Some(room_id)).await {
+            warn!(err:? = e, user:? = session_id; "Could not leave match");
+        }
+    }
+
+    async fn expire(&self, session_id: String, ticket: u64) {
+        if !self.queue.lock().await.expire(&session_id, ticket) {
+            return;
+        }
+
+        info!(user:? = session_id; "Partner waiting timed out");
+        if let Err(e) = self
+            .signalling
+            .send_matchmaking(session_id.clone(), MatchmakingEvent::Timeout)
+            .await
+        {
+            warn!(err:? = e, user:? = session_id; "Could not send queue timeout");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webrtc::sfu::tests::{sfu, Recorder};
+
+    async fn room_of(matchmaker: &Matchmaker, session_id: &str) -> Option<String> {
+        matchmaker
+            .matches
+            .lock()
+            .await
+            .get(session_id)
+            .map(|m| m.room_id.clone())
+    }
+
+    #[tokio::test]
+    async fn stale_left_keeps_new_match() {
+        let signalling = Arc::new(Recorder::default());
+        let sfu = sfu(&signalling, Duration::from_secs(15));
+        let matchmaker =
+            Matchmaker::new(sfu, Arc::clone(&signalling) as _, Duration::from_secs(60));
+
+        matchmaker.join("1-ws-a".to_string(), 1).await.unwrap();
+        matchmaker.join("2-ws-b".to_string(), 2).await.unwrap();
+        let old_room = room_of(&matchmaker, "1-ws-a").await.unwrap();
+
+        // a уходит к новому собеседнику, c сразу составляет с ним пару
+        matchmaker.next("1-ws-a".to_string(), 1).await.unwrap();
+        matchmaker.join("3-ws-c".to_string(), 3).await.unwrap();
+        let new_room = room_of(&matchmaker, "1-ws-a").await.unwrap();
+        assert_ne!(new_room, old_room);
+
+        // Left о старой комнате приходит после новой пары
+        matchmaker.on_left(&old_room, "1-ws-a").await;
+        assert_eq!(room_of(&matchmaker, "1-ws-a").await, Some(new_room.clone()));
+        assert_eq!(room_of(&matchmaker, "3-ws-c").await, Some(new_room.clone()));
+        assert!(!signalling
+            .matchmaking
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(to, event)| to == "3-ws-c"
+                && matches!(event, MatchmakingEvent::PartnerLeft { .. })));
+
+        // Left о текущей комнате распадает пару
+        matchmaker.on_left(&new_room, "1-ws-a").await;
+        assert_eq!(room_of(&matchmaker, "3-ws-c").await, None);
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone)]
+pub struct Waiting {
+    pub session_id: String,
+    pub user_id: i64,
+    pub ticket: u64,
+}
+
+#[derive(Debug)]
+pub enum Joined {
+    // Подходящего партнера нет, пользователь встал в очередь
+    Waiting(u64),
+    // Партнер найден и уже удален из очереди
+    Paired(Waiting),
+}
+
+// Очередь ожидания собеседника (FIFO).
+// Тикет нужен чтобы таймер ожидания не выкинул пользователя, который успел выйти и зайти в очередь заново.
+#[derive(Default)]
+pub struct WaitingQueue {
+    entries: VecDeque<Waiting>,
+    next_ticket: u64,
+}
+
+impl WaitingQueue {
+    pub fn join(&mut self, session_id: String, user_id: i64) -> Joined {
+        self.cancel(&session_id);
+
+        // Не соединяем пользователя самого с собой (например, две вкладки)
+        let partner = self.entries.iter().position(|w| w.user_id != user_id);
+        if let Some(partner) = partner.and_then(|i| self.entries.remove(i)) {
+            return Joined::Paired(partner);
+        }
+
+        self.next_ticket += 1;
+        self.entries.push_back(Waiting {
+            session_id,
+            user_id,
+            ticket: self.next_ticket,
+        });
+
+        Joined::Waiting(self.next_ticket)
+    }
+
+    pub fn cancel(&mut self, session_id: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|w| w.session_id != session_id);
+        len != self.entries.len()
+    }
+
+    pub fn expire(&mut self, session_id: &str, ticket: u64) -> bool {
+        let len = self.entries.len();
+        self.entries
+            .retain(|w| w.session_id != session_id || w.ticket != ticket);
+        len != self.entries.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairs_two_users() {
+        let mut queue = WaitingQueue::default();
+        assert!(matches!(queue.join("1".into(), 1), Joined::Waiting(_)));
+
+        match queue.join("2".into(), 2) {
+            Joined::Paired(partner) => assert_eq!(partner.session_id, "1"),
+            Joined::Waiting(_) => panic!("expected pair"),
+        }
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn does_not_pair_user_with_himself() {
+        let mut queue = WaitingQueue::default();
+        queue.join("1".into(), 1);
+        assert!(matches!(queue.join("1-b".into(), 1), Joined::Waiting(_)));
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn rejoin_replaces_entry() {
+        let mut queue = WaitingQueue::default();
+        queue.join("1".into(), 1);
+        queue.join("1".into(), 1);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn stale_ticket_does_not_expire_new_entry() {
+        let mut queue = WaitingQueue::default();
+        let Joined::Waiting(old) = queue.join("1".into(), 1) else {
+            panic!("expected waiting")
+        };
+        queue.cancel("1");
+        let Joined::Waiting(new) = queue.join("1".into(), 1) else {
+            panic!("expected waiting")
+        };
+
+        assert!(!queue.expire("1", old));
+        assert!(queue.expire("1", new));
+        assert_eq!(queue.len(), 0);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::extract::jwt::AdminJwt;
+use crate::webrtc::axum::{AppError, WebrtcState};
+use crate::webrtc::sfu::Role;
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::NaiveDateTime;
+use http::StatusCode;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+// Администрирование комнат, доступно только токенам с ролью admin
+
+#[derive(Serialize, Debug)]
+pub struct TrackInfo {
+    pub id: String,
+    pub kind: &'static str,
+    pub mime_type: String,
+    pub subscribers: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ParticipantInfo {
+    pub session_id: String,
+    pub user_id: i64,
+    pub role: Role,
+    pub state: String,
+    pub joined_at: NaiveDateTime,
+    pub tracks: Vec<TrackInfo>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RoomInfo {
+    pub room_id: String,
+    pub capacity: Option<usize>,
+    pub age_secs: u64,
+    pub recording: bool,
+    pub participants: Vec<ParticipantInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BroadcastRequest {
+    // None - всем комнатам
+    room_id: Option<String>,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct BroadcastResponse {
+    recipients: usize,
+}
+
+pub async fn rooms(
+    AdminJwt(_): AdminJwt,
+    State(app_state): State<WebrtcState>,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(Json(app_state.sfu.rooms_overview().await))
+}
+
+pub async fn close_room(
+    AdminJwt(claims): AdminJwt,
+    State(app_state): State<WebrtcState>,
+    Path(room_id): Path<String>,
+) -> Result<Response, AppError> {
+    info!(admin:? = claims.sub, room:? = room_id; "Admin closes room");
+    if !app_state.sfu.close_room(&room_id).await {
+        return Ok((StatusCode::NOT_FOUND, "room not found").into_response());
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+pub async fn kick(
+    AdminJwt(claims): AdminJwt,
+    State(app_state): State<WebrtcState>,
+    Path((room_id, session_id)): Path<(String, String)>,
+) -> Result<Response, AppError> {
+    if app_state
+        .sfu
+        .find_peer(&session_id, &room_id)
+        .await
+        .is_none()
+    {
+        return Ok((StatusCode::NOT_FOUND, "participant not found").into_response());
+    }
+
+    info!(admin:? = claims.sub, room:? = room_id, user:? = session_id; "Admin closes peer connection");
+    app_state.sfu.leave(&session_id, &room_id).await?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+pub async fn broadcast(
+    AdminJwt(claims): AdminJwt,
+    State(app_state): State<WebrtcState>,
+    Json(req): Json<BroadcastRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    info!(admin:? = claims.sub, room:? = req.room_id; "Admin broadcasts system message");
+    let recipients = app_state
+        .sfu
+        .broadcast(req.room_id.as_deref(), &req.message)
+        .await;
+
+    Ok(Json(BroadcastResponse { recipients }))
+}
@@ -1,15 +1,32 @@
+use crate::account::AccountClient;
+use crate::cluster::registry::{ClusterRegistry, FileRegistry, MemoryRegistry};
+use crate::cluster::{
+    Cluster, ForwardedMedia, ForwardedRequest, ForwardedResponse, ForwardedSignal, Node, NodeError,
+    Route,
+};
 use crate::extract::jwt::{Jwt, SecretKey};
-use crate::webrtc::sfu::{Sfu, Signalling};
-use anyhow::Result;
-use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{FromRef, State, WebSocketUpgrade};
+use crate::matchmaking::{Matchmaker, MatchmakingEvent};
+use crate::webrtc::bwe::BandwidthStats;
+use crate::webrtc::codec::CodecPolicy;
+use crate::webrtc::config::SfuConfig;
+use crate::webrtc::ice::{IceConfig, TurnCredentials};
+use crate::webrtc::metrics::metrics;
+use crate::webrtc::recording::{LocalStorage, RecordingError, RecordingStorage};
+use crate::webrtc::room::RoomError;
+use crate::webrtc::session::{self, SessionError, SessionPolicy};
+use crate::webrtc::sfu::{PracticeHistory, RoomEvent, Sfu, Signalling, UserDirectory};
+use crate::webrtc::simulcast::LayerQuality;
+use crate::webrtc::{admin, whep, whip};
+use crate::Args;
+use anyhow::{anyhow, Result};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use axum::extract::{FromRef, Query, State, WebSocketUpgrade};
 use axum::response::{IntoResponse, Response};
-use axum::routing::{any, post};
+use axum::routing::{any, delete, get, patch, post};
 use axum::{Json, Router};
-use futures::executor::block_on;
 use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt};
-use http::StatusCode;
+use http::{HeaderMap, StatusCode};
 use jsonwebtoken::errors::ErrorKind;
 use jsonwebtoken::DecodingKey;
 use log::{error, info, warn};
@@ -21,9 +38,14 @@ use std::pin::Pin;
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
 use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
+// Код закрытия websocket, который заменила новая сессия пользователя
+const SESSION_REPLACED: u16 = 4001;
+
 pub(crate) type SocketClient = (
     Mutex<SplitSink<WebSocket, Message>>,
     Mutex<SplitStream<WebSocket>>,
@@ -32,7 +54,12 @@ pub(crate) type SocketClient = (
 #[derive(Clone)]
 pub struct WebrtcState {
     pub(crate) sessions: Arc<Mutex<HashMap<String, Arc<SocketClient>>>>,
+    // Сессии, чей websocket закрылся: их можно продолжить в течение reconnect_window
+    pub(crate) detached: Arc<Mutex<HashMap<String, Instant>>>,
     pub(crate) sfu: Sfu,
+    pub(crate) matchmaker: Matchmaker,
+    pub(crate) cluster: Cluster,
+    pub(crate) session_policy: SessionPolicy,
     pub secret_key: SecretKey,
 }
 
@@ -45,20 +72,154 @@ impl FromRef<WebrtcState> for SecretKey {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type", content = "playground")]
 pub enum SignalingResponse {
+    #[serde(rename = "session")]
+    Session(SessionInfo),
+
     #[serde(rename = "sdp")]
     Sdp(Box<RTCSessionDescription>),
 
     #[serde(rename = "candidate")]
     Candidate(Option<RTCIceCandidate>),
+
+    #[serde(rename = "matchmaking")]
+    Matchmaking(MatchmakingEvent),
+
+    #[serde(rename = "room")]
+    Room(RoomEvent),
+
+    #[serde(rename = "ack")]
+    Ack(Ack),
+
+    #[serde(rename = "error")]
+    Error(ErrorResponse),
+}
+
+// Первое сообщение websocket: id сессии для HTTP запросов сигналинга
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionInfo {
+    session_id: String,
+    // Сокет продолжил прежнюю сессию из запроса
+    resumed: bool,
+}
+
+// Подтверждение обработки запроса клиента, id совпадает с id запроса
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Ack {
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    answer: Option<Box<RTCSessionDescription>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResponse {
+    id: Option<String>,
+    message: String,
+}
+
+// Сообщения, которые клиент присылает в websocket
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", content = "playground")]
+pub enum SignalingRequest {
+    #[serde(rename = "queue_join")]
+    QueueJoin,
+
+    #[serde(rename = "queue_cancel")]
+    QueueCancel,
+
+    #[serde(rename = "next")]
+    Next,
+
+    #[serde(rename = "join")]
+    Join(RoomRequest),
+
+    #[serde(rename = "leave")]
+    Leave(RoomRequest),
+
+    #[serde(rename = "offer")]
+    Offer(AcceptOfferReq),
+
+    #[serde(rename = "answer")]
+    Answer(AcceptAnswerReq),
+
+    #[serde(rename = "candidate")]
+    Candidate(CandidateRequest),
+
+    #[serde(rename = "mute")]
+    Mute(MuteRequest),
+
+    #[serde(rename = "pause")]
+    Pause(PauseRequest),
+
+    #[serde(rename = "layer")]
+    Layer(LayerRequest),
+}
+
+// Запрос клиента вместе с необязательным id для сопоставления с ack/error
+#[derive(Deserialize, Debug)]
+struct IncomingMessage {
+    id: Option<String>,
+    #[serde(flatten)]
+    request: SignalingRequest,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RoomRequest {
+    room_id: String,
+}
+
+// Издатель выключает или включает свой трек
+#[derive(Deserialize, Serialize, Debug)]
+pub struct MuteRequest {
+    room_id: String,
+    track_id: String,
+    muted: bool,
+}
+
+// Подписчик приостанавливает или возобновляет получение видео участника
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PauseRequest {
+    room_id: String,
+    publisher_id: String,
+    paused: bool,
+}
+
+// Желаемое качество simulcast видео от конкретного участника
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LayerRequest {
+    publisher_id: String,
+    quality: LayerQuality,
+    // Нужен, только если комната может быть на другом узле кластера
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    room_id: Option<String>,
+}
+
+impl SignalingRequest {
+    // Комната запроса и может ли запрос ее создать
+    fn room(&self) -> Option<(&str, bool)> {
+        match self {
+            SignalingRequest::Join(req) => Some((&req.room_id, true)),
+            SignalingRequest::Offer(req) => Some((&req.room_id, true)),
+            SignalingRequest::Candidate(req) => Some((&req.room_id, true)),
+            SignalingRequest::Leave(req) => Some((&req.room_id, false)),
+            SignalingRequest::Answer(req) => Some((&req.room_id, false)),
+            SignalingRequest::Mute(req) => Some((&req.room_id, false)),
+            SignalingRequest::Pause(req) => Some((&req.room_id, false)),
+            SignalingRequest::Layer(req) => req.room_id.as_deref().map(|room_id| (room_id, false)),
+            SignalingRequest::QueueJoin
+            | SignalingRequest::QueueCancel
+            | SignalingRequest::Next => None,
+        }
+    }
 }
 
 struct WebsocketSignalling {
     sessions: Arc<Mutex<HashMap<String, Arc<SocketClient>>>>,
+    cluster: Cluster,
 }
 
 impl WebsocketSignalling {
-    fn new(sessions: Arc<Mutex<HashMap<String, Arc<SocketClient>>>>) -> Self {
-        Self { sessions }
+    fn new(sessions: Arc<Mutex<HashMap<String, Arc<SocketClient>>>>, cluster: Cluster) -> Self {
+        Self { sessions, cluster }
     }
 }
 
@@ -68,28 +229,34 @@ pub enum SfuError {
     SessionNotFound,
 }
 
+impl WebsocketSignalling {
+    async fn send(&self, session_id: String, response: SignalingResponse) -> Result<()> {
+        let session = self.sessions.lock().await.get(&session_id).cloned();
+        if let Some(session) = session {
+            return send_reply(&session, &response).await;
+        }
+
+        // websocket пользователя может быть открыт на другом узле кластера
+        match self.cluster.session_node(&session_id).await? {
+            Some(node) => {
+                let signal = ForwardedSignal {
+                    session_id,
+                    response,
+                };
+                self.cluster.forward_signal(&node, &signal).await
+            }
+            None => Err(SfuError::SessionNotFound.into()),
+        }
+    }
+}
+
 impl Signalling for WebsocketSignalling {
     fn send_sdp(
         &self,
         session_id: String,
         sdp: RTCSessionDescription,
     ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
-        Box::pin(async move {
-            let sessions = self.sessions.lock().await;
-            let session = sessions.get(&session_id);
-            if let Some(session) = session {
-                let playground = serde_json::to_string(&SignalingResponse::Sdp(Box::new(sdp)))?;
-                session
-                    .0
-                    .lock()
-                    .await
-                    .send(Message::from(playground))
-                    .await?;
-                Ok(())
-            } else {
-                Err(SfuError::SessionNotFound.into())
-            }
-        })
+        Box::pin(self.send(session_id, SignalingResponse::Sdp(Box::new(sdp))))
     }
 
     fn send_ice_candidate(
@@ -97,28 +264,33 @@ impl Signalling for WebsocketSignalling {
         session_id: String,
         candidate: Option<RTCIceCandidate>,
     ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
-        Box::pin(async move {
-            let sessions = self.sessions.lock().await;
-            let session = sessions.get(&session_id);
-            if let Some(session) = session {
-                let playground = serde_json::to_string(&SignalingResponse::Candidate(candidate))?;
-                session
-                    .0
-                    .lock()
-                    .await
-                    .send(Message::from(playground))
-                    .await?;
-                Ok(())
-            } else {
-                Err(SfuError::SessionNotFound.into())
-            }
-        })
+        Box::pin(self.send(session_id, SignalingResponse::Candidate(candidate)))
+    }
+
+    fn send_matchmaking(
+        &self,
+        session_id: String,
+        event: MatchmakingEvent,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(self.send(session_id, SignalingResponse::Matchmaking(event)))
+    }
+
+    fn send_room_event(
+        &self,
+        session_id: String,
+        event: RoomEvent,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(self.send(session_id, SignalingResponse::Room(event)))
     }
 }
 
-pub fn create_webrtc_state() -> WebrtcState {
+pub fn create_webrtc_state(args: &Args) -> Result<WebrtcState> {
+    let cluster = cluster(args);
     let sessions = Arc::new(Mutex::new(HashMap::new()));
-    let signalling = Box::new(WebsocketSignalling::new(Arc::clone(&sessions)));
+    let signalling: Arc<dyn Signalling> = Arc::new(WebsocketSignalling::new(
+        Arc::clone(&sessions),
+        cluster.clone(),
+    ));
 
     let secret_key = {
         let key = env::var_os("SECRET_KEY")
@@ -131,57 +303,658 @@ pub fn create_webrtc_state() -> WebrtcState {
         Box::leak(Box::new(key))
     } as SecretKey; // allow SECRET_KEY life endless
 
-    WebrtcState {
-        sfu: Sfu::new(signalling),
+    let config = SfuConfig {
+        room_capacity: args.room_capacity,
+        empty_room_ttl: Duration::from_secs(args.empty_room_ttl),
+        ice: ice_config(args),
+        codecs: CodecPolicy {
+            video: args.video_codecs.clone(),
+            opus_fec: args.opus_fec,
+            opus_dtx: args.opus_dtx,
+        },
+        chat_history: args.chat_history,
+        reconnect_window: Duration::from_secs(args.reconnect_window),
+    };
+    let account = match (&args.account_url, &args.internal_api_token) {
+        (Some(url), Some(token)) => Some(Arc::new(AccountClient::new(url.clone(), token.clone()))),
+        (Some(_), None) => {
+            warn!("ACCOUNT_URL is configured without INTERNAL_API_TOKEN, talk history and display names are disabled");
+            None
+        }
+        _ => None,
+    };
+    let history = account
+        .clone()
+        .map(|account| account as Arc<dyn PracticeHistory>);
+    let directory = account.map(|account| account as Arc<dyn UserDirectory>);
+    let recordings = args.recordings_dir.as_ref().map(|dir| {
+        info!(dir:? = dir; "Room recording is enabled");
+        Arc::new(LocalStorage::new(dir.clone())) as Arc<dyn RecordingStorage>
+    });
+    let sfu = Sfu::new(
+        Arc::clone(&signalling),
+        config,
+        history,
+        recordings,
+        directory,
+    )?;
+    sfu.spawn_room_gc();
+    sfu.spawn_bandwidth_controller();
+    sfu.spawn_speaker_detection();
+    cluster.spawn_heartbeat();
+    cluster.spawn_room_release(&sfu);
+
+    let matchmaker = Matchmaker::new(
+        sfu.clone(),
+        signalling,
+        Duration::from_secs(args.queue_timeout),
+    );
+
+    Ok(WebrtcState {
+        sfu,
+        matchmaker,
         sessions: Arc::clone(&sessions),
+        detached: Default::default(),
+        cluster,
+        session_policy: args.session_policy,
         secret_key,
+    })
+}
+
+fn cluster(args: &Args) -> Cluster {
+    let node = Node {
+        id: args
+            .node_id
+            .clone()
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+        url: args
+            .node_url
+            .clone()
+            .unwrap_or_else(|| format!("http://127.0.0.1:{}", args.port)),
+    };
+
+    let registry: Arc<dyn ClusterRegistry> = match &args.cluster_dir {
+        Some(dir) => {
+            info!(node:? = node.id, dir:? = dir; "Cluster mode is enabled");
+            if args.internal_api_token.is_none() {
+                warn!("Cluster mode needs INTERNAL_API_TOKEN to forward requests between nodes");
+            }
+            Arc::new(FileRegistry::new(dir.clone()))
+        }
+        None => Arc::new(MemoryRegistry::default()),
+    };
+
+    Cluster::new(
+        node,
+        registry,
+        args.internal_api_token.clone(),
+        Duration::from_secs(args.node_lease),
+    )
+}
+
+fn ice_config(args: &Args) -> IceConfig {
+    let turn_credentials = match (
+        &args.turn_secret,
+        &args.turn_username,
+        &args.turn_credential,
+    ) {
+        (Some(secret), _, _) => Some(TurnCredentials::Rest {
+            secret: secret.clone(),
+            ttl: Duration::from_secs(args.turn_ttl),
+        }),
+        (None, Some(username), Some(credential)) => Some(TurnCredentials::Static {
+            username: username.clone(),
+            credential: credential.clone(),
+        }),
+        _ => None,
+    };
+
+    if !args.turn_urls.is_empty() && turn_credentials.is_none() {
+        warn!("TURN urls are configured without credentials and will be ignored");
+    }
+
+    IceConfig {
+        stun_urls: args.stun_urls.clone(),
+        turn_urls: args.turn_urls.clone(),
+        turn_credentials,
     }
 }
 
 pub fn create_webrtc_router() -> Router<WebrtcState> {
     Router::new()
         .route("/ws", any(ws))
+        .route("/ice-servers", get(ice_servers))
+        .route("/stats", get(stats))
+        .route("/metrics", get(prometheus_metrics))
+        .route("/talk-summary", get(talk_summary))
+        .route("/recordings/start", post(start_recording))
+        .route("/recordings/stop", post(stop_recording))
         .route("/offer", post(accept_offer))
         .route("/answer", post(accept_answer))
         .route("/candidate", post(candidate))
+        .route("/internal/cluster/request", post(cluster_request))
+        .route("/internal/cluster/signal", post(cluster_signal))
+        .route("/internal/cluster/media", post(cluster_media))
+        .route("/whip/{room_id}", post(whip::publish))
+        .route(
+            "/whip/{room_id}/{session_id}",
+            patch(whip::trickle).delete(whip::teardown),
+        )
+        .route("/admin/rooms", get(admin::rooms))
+        .route("/admin/rooms/{room_id}", delete(admin::close_room))
+        .route(
+            "/admin/rooms/{room_id}/participants/{session_id}",
+            delete(admin::kick),
+        )
+        .route("/admin/broadcast", post(admin::broadcast))
+        .route("/whep/{room_id}", post(whep::watch))
+        .route(
+            "/whep/{room_id}/{session_id}",
+            patch(whip::trickle).delete(whip::teardown),
+        )
 }
 
+// ?session_id= продолжает прежнюю сессию пользователя после обрыва websocket или соединения с SFU
 async fn ws(
     ws: WebSocketUpgrade,
     Jwt(claims): Jwt,
     State(app_state): State<WebrtcState>,
-) -> Result<impl IntoResponse, AppError> {
-    let sessions = app_state.sessions.clone();
-
-    let session_id = claims.sub.to_string();
+    Query(query): Query<SessionQuery>,
+) -> Result<Response, AppError> {
+    // Остановка узла: клиент должен подключиться к другому
+    if app_state.sfu.is_draining() {
+        return Err(RoomError::Draining.into());
+    }
 
     let resp = ws
         .on_failed_upgrade(move |e| {
             warn!(err:? = e; "Websocket upgrade failed");
-
-            block_on(sessions.lock()).remove(&claims.sub.to_string());
         })
         .on_upgrade(async move |socket| {
             let (sender, receiver) = socket.split();
             let socket_client: Arc<SocketClient> =
                 Arc::new((Mutex::new(sender), Mutex::new(receiver)));
 
-            info!(session_id:? = session_id; "Websocket client connected");
+            // Каждая вкладка и устройство получают свою сессию
+            let resumed = match query.session_id {
+                Some(session_id) if resumable(&app_state, claims.sub, &session_id).await => {
+                    Some(session_id)
+                }
+                _ => None,
+            };
+            let session_id = resumed
+                .clone()
+                .unwrap_or_else(|| session::new_session_id(claims.sub, session::WS));
+            info!(user:? = claims.sub, session_id:? = session_id, resumed:? = resumed.is_some(); "Websocket client connected");
 
-            app_state
+            let previous = app_state
                 .sessions
                 .lock()
                 .await
-                .insert(session_id, Arc::clone(&socket_client));
+                .insert(session_id.clone(), Arc::clone(&socket_client));
+            // Прежний сокет сессии еще открыт, например клиент не заметил обрыва
+            if let Some(previous) = previous {
+                _ = previous.0.lock().await.send(Message::Close(None)).await;
+            }
+            app_state.cluster.register_session(&session_id).await;
+
+            if app_state.session_policy == SessionPolicy::Replace {
+                replace_sessions(&app_state, claims.sub, &session_id).await;
+            }
+
+            let hello = SignalingResponse::Session(SessionInfo {
+                session_id: session_id.clone(),
+                resumed: resumed.is_some(),
+            });
+            if let Err(e) = send_reply(&socket_client, &hello).await {
+                warn!(err:? = e, session_id:? = session_id; "Could not send session id");
+            }
+            if resumed.is_some() {
+                app_state.sfu.resume(&session_id).await;
+            }
+
+            // Сообщения обрабатываются последовательно: кандидаты не должны обгонять offer
+            {
+                let mut receiver = socket_client.1.lock().await;
+                while let Some(Ok(message)) = receiver.next().await {
+                    let text = match message {
+                        Message::Text(text) => text,
+                        Message::Close(_) => break,
+                        _ => continue,
+                    };
+
+                    let reply = match serde_json::from_str::<IncomingMessage>(text.as_str()) {
+                        Ok(IncomingMessage { id, request }) => {
+                            match handle_request(&app_state, &session_id, claims.sub, request).await
+                            {
+                                Ok(answer) => SignalingResponse::Ack(Ack {
+                                    id,
+                                    answer: answer.map(Box::new),
+                                }),
+                                Err(e) => {
+                                    warn!(err:? = e, session_id:? = session_id; "Failed to handle websocket message");
+                                    SignalingResponse::Error(ErrorResponse {
+                                        id,
+                                        message: e.to_string(),
+                                    })
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            warn!(err:? = e, session_id:? = session_id; "Invalid websocket message");
+                            SignalingResponse::Error(ErrorResponse {
+                                id: None,
+                                message: e.to_string(),
+                            })
+                        }
+                    };
+
+                    if let Err(e) = send_reply(&socket_client, &reply).await {
+                        warn!(err:? = e, session_id:? = session_id; "Could not reply to websocket message");
+                        break;
+                    }
+                }
+            }
+
+            info!(session_id:? = session_id; "Websocket client disconnected");
+
+            // сессию могла уже закрыть новая сессия того же пользователя или продолжить новый сокет
+            let removed = {
+                let mut sessions = app_state.sessions.lock().await;
+                match sessions.get(&session_id) {
+                    Some(socket) if Arc::ptr_eq(socket, &socket_client) => {
+                        sessions.remove(&session_id).is_some()
+                    }
+                    _ => false,
+                }
+            };
+            if removed {
+                app_state.matchmaker.leave_queue(&session_id).await;
+                detach(&app_state, session_id).await;
+            }
         });
 
     Ok(resp)
 }
 
-#[derive(Deserialize, Serialize)]
-struct AcceptOfferReq {
+// Сессию продолжает только ее владелец, пока она ждет нового сокета или соединение с SFU восстанавливается
+async fn resumable(app_state: &WebrtcState, user_id: i64, session_id: &str) -> bool {
+    if !session::resumable(session_id, user_id) {
+        return false;
+    }
+    app_state.detached.lock().await.remove(session_id).is_some()
+        || app_state.sfu.in_reconnect_window(session_id).await
+}
+
+// Сессия из HTTP запроса должна принадлежать пользователю из JWT и быть выданной сервером:
+// ее websocket открыт или ждет переподключения на этом узле либо открыт на другом узле кластера
+async fn verify_session(
+    app_state: &WebrtcState,
+    session_id: Option<String>,
+    user_id: i64,
+) -> Result<String> {
+    let session_id = session::verify(session_id, user_id)?;
+    if app_state.sessions.lock().await.contains_key(&session_id)
+        || app_state.detached.lock().await.contains_key(&session_id)
+        || app_state.cluster.session_node(&session_id).await?.is_some()
+    {
+        return Ok(session_id);
+    }
+    Err(SessionError::Unknown.into())
+}
+
+// Сессия без сокета ждет переподключения reconnect_window, потом покидает пару и забывается
+async fn detach(app_state: &WebrtcState, session_id: String) {
+    let detached_at = Instant::now();
+    app_state
+        .detached
+        .lock()
+        .await
+        .insert(session_id.clone(), detached_at);
+
+    let app_state = app_state.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(app_state.sfu.config.reconnect_window).await;
+        let expired = {
+            let mut detached = app_state.detached.lock().await;
+            if detached.get(&session_id) == Some(&detached_at) {
+                detached.remove(&session_id);
+                true
+            } else {
+                false
+            }
+        };
+        if expired {
+            info!(session_id:? = session_id; "Websocket session did not resume in time");
+            app_state.matchmaker.disconnect(&session_id).await;
+            app_state.cluster.remove_session(&session_id).await;
+        }
+    });
+}
+
+// Политика replace: новая сессия закрывает прежние websocket и соединения пользователя.
+// Действует в пределах узла, сессии на других узлах кластера остаются.
+async fn replace_sessions(app_state: &WebrtcState, user_id: i64, current: &str) {
+    let replaced = {
+        let mut sessions = app_state.sessions.lock().await;
+        let ids = sessions
+            .keys()
+            .filter(|id| id.as_str() != current && session::owner(id) == Some(user_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        ids.into_iter()
+            .filter_map(|id| sessions.remove(&id).map(|socket| (id, socket)))
+            .collect::<Vec<_>>()
+    };
+
+    for (session_id, socket_client) in replaced {
+        info!(user:? = user_id, session_id:? = session_id; "Websocket session replaced by a new one");
+        app_state.cluster.remove_session(&session_id).await;
+        app_state.matchmaker.disconnect(&session_id).await;
+        let frame = CloseFrame {
+            code: SESSION_REPLACED,
+            reason: "session replaced".into(),
+        };
+        _ = socket_client
+            .0
+            .lock()
+            .await
+            .send(Message::Close(Some(frame)))
+            .await;
+    }
+
+    app_state.sfu.close_user_sessions(user_id, current).await;
+}
+
+// Остановка узла: предупреждает клиентов, дает комнатам опустеть и закрывает оставшиеся соединения
+pub async fn drain(app_state: WebrtcState, grace: Duration) {
+    app_state.sfu.start_draining(grace).await;
+
+    if !app_state.sfu.wait_rooms_empty(grace).await {
+        warn!("Grace period is over, closing remaining peer connections");
+    }
+    app_state.sfu.close_all_rooms().await;
+
+    // Иначе axum будет ждать завершения websocket соединений
+    let sessions = app_state.sessions.lock().await.drain().collect::<Vec<_>>();
+    for (session_id, socket_client) in sessions {
+        app_state.cluster.remove_session(&session_id).await;
+        _ = socket_client
+            .0
+            .lock()
+            .await
+            .send(Message::Close(None))
+            .await;
+    }
+    info!("Node drained");
+}
+
+async fn send_reply(socket_client: &SocketClient, reply: &SignalingResponse) -> Result<()> {
+    let playground = serde_json::to_string(reply)?;
+    socket_client
+        .0
+        .lock()
+        .await
+        .send(Message::from(playground))
+        .await?;
+    Ok(())
+}
+
+// Возвращает answer, если запрос был offer. Запросы к комнате на другом узле проксируются туда.
+async fn handle_request(
+    app_state: &WebrtcState,
+    session_id: &str,
+    user_id: i64,
+    req: SignalingRequest,
+) -> Result<Option<RTCSessionDescription>> {
+    if let Some((room_id, creates)) = req.room() {
+        let route = if creates {
+            app_state.cluster.claim(room_id).await?
+        } else {
+            app_state.cluster.locate(room_id).await?
+        };
+
+        if let Route::Remote(node) = route {
+            return forward(app_state, &node, session_id.to_string(), user_id, req).await;
+        }
+    }
+
+    handle_local_request(app_state, session_id, user_id, req).await
+}
+
+async fn forward(
+    app_state: &WebrtcState,
+    node: &Node,
+    session_id: String,
+    user_id: i64,
+    request: SignalingRequest,
+) -> Result<Option<RTCSessionDescription>> {
+    let request = ForwardedRequest {
+        session_id,
+        user_id,
+        request,
+    };
+    app_state.cluster.forward_request(node, &request).await
+}
+
+async fn handle_local_request(
+    app_state: &WebrtcState,
+    session_id: &str,
+    user_id: i64,
+    req: SignalingRequest,
+) -> Result<Option<RTCSessionDescription>> {
+    let session_id = session_id.to_string();
+    match req {
+        SignalingRequest::QueueJoin => app_state.matchmaker.join(session_id, user_id).await?,
+        SignalingRequest::QueueCancel => app_state.matchmaker.cancel(session_id).await?,
+        SignalingRequest::Next => app_state.matchmaker.next(session_id, user_id).await?,
+        SignalingRequest::Join(req) => app_state.sfu.join(session_id, req.room_id).await?,
+        SignalingRequest::Leave(req) => app_state.sfu.leave(&session_id, &req.room_id).await?,
+        SignalingRequest::Offer(req) => {
+            let answer = app_state
+                .sfu
+                .accept_offer(session_id, req.offer, req.room_id)
+                .await?;
+            return Ok(Some(answer));
+        }
+        SignalingRequest::Answer(req) => {
+            app_state
+                .sfu
+                .accept_answer(session_id, req.answer, req.room_id)
+                .await?
+        }
+        SignalingRequest::Candidate(req) => {
+            app_state
+                .sfu
+                .accept_candidate(session_id, req.room_id, req.candidate)
+                .await?
+        }
+        SignalingRequest::Mute(req) => {
+            app_state
+                .sfu
+                .set_track_muted(&session_id, &req.room_id, &req.track_id, req.muted)
+                .await?
+        }
+        SignalingRequest::Pause(req) => {
+            app_state
+                .sfu
+                .set_video_paused(&session_id, &req.publisher_id, req.paused)
+                .await
+        }
+        SignalingRequest::Layer(req) => {
+            app_state
+                .sfu
+                .set_layer_quality(&session_id, &req.publisher_id, req.quality)
+                .await
+        }
+    }
+
+    Ok(None)
+}
+
+// Запрос из websocket, открытого на другом узле кластера
+async fn cluster_request(
+    State(app_state): State<WebrtcState>,
+    headers: HeaderMap,
+    Json(req): Json<ForwardedRequest>,
+) -> Result<Response, AppError> {
+    if !app_state.cluster.authorize(&headers) {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let answer =
+        handle_local_request(&app_state, &req.session_id, req.user_id, req.request).await?;
+
+    Ok(Json(ForwardedResponse { answer }).into_response())
+}
+
+// Запрос WHIP/WHEP, принятый другим узлом кластера
+async fn cluster_media(
+    State(app_state): State<WebrtcState>,
+    headers: HeaderMap,
+    Json(req): Json<ForwardedMedia>,
+) -> Result<Response, AppError> {
+    if !app_state.cluster.authorize(&headers) {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let answer =
+        whip::handle_local_media(&app_state, req.session_id, req.room_id, req.request).await?;
+
+    Ok(Json(ForwardedResponse { answer }).into_response())
+}
+
+// Сообщение SFU другого узла для websocket на этом узле
+async fn cluster_signal(
+    State(app_state): State<WebrtcState>,
+    headers: HeaderMap,
+    Json(req): Json<ForwardedSignal>,
+) -> Result<Response, AppError> {
+    if !app_state.cluster.authorize(&headers) {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let session = app_state
+        .sessions
+        .lock()
+        .await
+        .get(&req.session_id)
+        .cloned();
+    let Some(session) = session else {
+        return Ok((StatusCode::NOT_FOUND, "session not found").into_response());
+    };
+    send_reply(&session, &req.response).await?;
+
+    Ok(Json(()).into_response())
+}
+
+#[derive(Serialize)]
+struct IceServersResponse {
+    ice_servers: Vec<RTCIceServer>,
+}
+
+async fn ice_servers(
+    Jwt(claims): Jwt,
+    State(app_state): State<WebrtcState>,
+) -> Result<impl IntoResponse, AppError> {
+    let ice_servers = app_state
+        .sfu
+        .config
+        .ice
+        .ice_servers(&claims.sub.to_string());
+
+    Ok(Json(IceServersResponse { ice_servers }))
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    bandwidth: Option<BandwidthStats>,
+}
+
+#[derive(Deserialize)]
+struct SessionQuery {
+    session_id: Option<String>,
+}
+
+// Статистика соединения сессии пользователя с SFU
+async fn stats(
+    Jwt(claims): Jwt,
+    State(app_state): State<WebrtcState>,
+    Query(query): Query<SessionQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let session_id = verify_session(&app_state, query.session_id, claims.sub).await?;
+    let bandwidth = app_state.sfu.bandwidth_stats(&session_id).await;
+
+    Ok(Json(StatsResponse { bandwidth }))
+}
+
+// Метрики для Prometheus
+async fn prometheus_metrics(
+    State(app_state): State<WebrtcState>,
+) -> Result<impl IntoResponse, AppError> {
+    app_state.sfu.update_metrics().await;
+    metrics()
+        .ws_sessions
+        .set(app_state.sessions.lock().await.len() as i64);
+
+    Ok((
+        [(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics().encode()?,
+    ))
+}
+
+// Сколько пользователь говорил и слушал в последнем разговоре
+async fn talk_summary(
+    Jwt(claims): Jwt,
+    State(app_state): State<WebrtcState>,
+) -> Result<impl IntoResponse, AppError> {
+    let summary = app_state.sfu.talk_summary(claims.sub).await;
+
+    match summary {
+        Some(summary) => Ok(Json(summary).into_response()),
+        None => Ok((StatusCode::NOT_FOUND, "no finished talks").into_response()),
+    }
+}
+
+#[derive(Serialize)]
+struct RecordingResponse {
+    recording_id: String,
+}
+
+async fn start_recording(
+    Jwt(claims): Jwt,
+    State(app_state): State<WebrtcState>,
+    Json(req): Json<RoomRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let recording_id = app_state
+        .sfu
+        .start_recording(claims.sub, &req.room_id)
+        .await?;
+
+    Ok(Json(RecordingResponse { recording_id }))
+}
+
+// Останавливает запись и возвращает ее метаданные
+async fn stop_recording(
+    Jwt(claims): Jwt,
+    State(app_state): State<WebrtcState>,
+    Json(req): Json<RoomRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let metadata = app_state
+        .sfu
+        .stop_recording(claims.sub, &req.room_id)
+        .await?;
+
+    Ok(Json(metadata))
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct AcceptOfferReq {
     offer: RTCSessionDescription,
     room_id: String,
+    // Для HTTP запроса: websocket сессия, в которую SFU шлет сигналинг
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -189,20 +962,32 @@ struct AnswerResponse {
     answer: RTCSessionDescription,
 }
 
+// HTTP сигналинг к комнате на другом узле проксируется туда так же, как запросы из websocket:
+// при перенаправлении на другой origin клиент потерял бы заголовок Authorization
 async fn accept_offer(
     Jwt(claims): Jwt,
     State(app_state): State<WebrtcState>,
     Json(req): Json<AcceptOfferReq>,
-) -> Result<impl IntoResponse, AppError> {
+) -> Result<Response, AppError> {
+    let session_id = verify_session(&app_state, req.session_id.clone(), claims.sub).await?;
+    if let Route::Remote(node) = app_state.cluster.claim(&req.room_id).await? {
+        let request = SignalingRequest::Offer(req);
+        let Some(answer) = forward(&app_state, &node, session_id, claims.sub, request).await?
+        else {
+            return Err(anyhow!("room node returned no answer").into());
+        };
+        return Ok(Json(AnswerResponse { answer }).into_response());
+    }
+
     let answer = app_state
         .sfu
-        .accept_offer(claims.sub.to_string(), req.offer, req.room_id)
+        .accept_offer(session_id, req.offer, req.room_id)
         .await?;
 
-    Ok(Json(AnswerResponse { answer }))
+    Ok(Json(AnswerResponse { answer }).into_response())
 }
 
-struct AppError(anyhow::Error);
+pub(crate) struct AppError(anyhow::Error);
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
@@ -214,6 +999,36 @@ impl IntoResponse for AppError {
             }
         };
 
+        if let Some(err) = self.0.downcast_ref::<RoomError>() {
+            let status = match err {
+                RoomError::Full => StatusCode::CONFLICT,
+                RoomError::Draining => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            return (status, err.to_string().to_lowercase()).into_response();
+        }
+
+        if let Some(err) = self.0.downcast_ref::<SessionError>() {
+            let status = match err {
+                SessionError::Missing => StatusCode::BAD_REQUEST,
+                SessionError::Foreign => StatusCode::FORBIDDEN,
+                SessionError::Unknown => StatusCode::NOT_FOUND,
+            };
+            return (status, err.to_string().to_lowercase()).into_response();
+        }
+
+        if let Some(err) = self.0.downcast_ref::<NodeError>() {
+            return (err.status, err.message.clone()).into_response();
+        }
+
+        if let Some(err) = self.0.downcast_ref::<RecordingError>() {
+            let status = match err {
+                RecordingError::Disabled => StatusCode::NOT_IMPLEMENTED,
+                RecordingError::NotInRoom => StatusCode::FORBIDDEN,
+                RecordingError::AlreadyStarted | RecordingError::NotStarted => StatusCode::CONFLICT,
+            };
+            return (status, err.to_string()).into_response();
+        }
+
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Something went wrong: {}", self.0),
@@ -231,40 +1046,106 @@ where
     }
 }
 
-#[derive(Deserialize, Serialize)]
-struct AcceptAnswerReq {
+#[derive(Deserialize, Serialize, Debug)]
+pub struct AcceptAnswerReq {
     answer: RTCSessionDescription,
     room_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
 }
 
 async fn accept_answer(
     Jwt(claims): Jwt,
     State(app_state): State<WebrtcState>,
     Json(req): Json<AcceptAnswerReq>,
-) -> Result<impl IntoResponse, AppError> {
+) -> Result<Response, AppError> {
+    let session_id = verify_session(&app_state, req.session_id.clone(), claims.sub).await?;
+    if let Route::Remote(node) = app_state.cluster.locate(&req.room_id).await? {
+        let request = SignalingRequest::Answer(req);
+        forward(&app_state, &node, session_id, claims.sub, request).await?;
+        return Ok("ok".into_response());
+    }
+
     app_state
         .sfu
-        .accept_answer(claims.sub.to_string().clone(), req.answer, req.room_id)
+        .accept_answer(session_id, req.answer, req.room_id)
         .await?;
 
-    Ok("ok")
+    Ok("ok".into_response())
 }
 
-#[derive(Deserialize, Serialize)]
-struct CandidateRequest {
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CandidateRequest {
     candidate: RTCIceCandidateInit,
     room_id: String, // TODO remove
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
 }
 
 async fn candidate(
     Jwt(claims): Jwt,
     State(app_state): State<WebrtcState>,
     Json(req): Json<CandidateRequest>,
-) -> Result<impl IntoResponse, AppError> {
+) -> Result<Response, AppError> {
+    let session_id = verify_session(&app_state, req.session_id.clone(), claims.sub).await?;
+    if let Route::Remote(node) = app_state.cluster.claim(&req.room_id).await? {
+        let request = SignalingRequest::Candidate(req);
+        forward(&app_state, &node, session_id, claims.sub, request).await?;
+        return Ok("ok".into_response());
+    }
+
     app_state
         .sfu
-        .accept_candidate(claims.sub.to_string(), req.room_id, req.candidate)
+        .accept_candidate(session_id, req.room_id, req.candidate)
         .await?;
 
-    Ok("ok")
+    Ok("ok".into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_with_id() {
+        let msg: IncomingMessage = serde_json::from_str(
+            r#"{"id": "1", "type": "leave", "playground": {"room_id": "abc"}}"#,
+        )
+        .unwrap();
+        assert_eq!(msg.id.as_deref(), Some("1"));
+        assert!(
+            matches!(msg.request, SignalingRequest::Leave(RoomRequest { room_id }) if room_id == "abc")
+        );
+    }
+
+    #[test]
+    fn parse_request_without_playground() {
+        let msg: IncomingMessage = serde_json::from_str(r#"{"type": "queue_join"}"#).unwrap();
+        assert!(msg.id.is_none());
+        assert!(matches!(msg.request, SignalingRequest::QueueJoin));
+    }
+
+    #[test]
+    fn parse_mute_request() {
+        let msg: IncomingMessage = serde_json::from_str(
+            r#"{"type": "mute", "playground": {"room_id": "abc", "track_id": "cam", "muted": true}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            msg.request,
+            SignalingRequest::Mute(MuteRequest { muted: true, .. })
+        ));
+    }
+
+    #[test]
+    fn serialize_ack() {
+        let ack = SignalingResponse::Ack(Ack {
+            id: Some("7".to_string()),
+            answer: None,
+        });
+        assert_eq!(
+            serde_json::to_string(&ack).unwrap(),
+            r#"{"type":"ack","playground":{"id":"7"}}"#
+        );
+    }
 }
@@ -0,0 +1,189 @@
+use serde::Serialize;
+use tokio::time::{Duration, Instant};
+
+pub const MIN_BITRATE: u64 = 100_000;
+const MAX_BITRATE: u64 = 20_000_000;
+// Оценка до первого REMB и первых отчетов о потерях
+const INITIAL_BITRATE: u64 = 2_500_000;
+// Браузер шлет REMB не реже раза в секунду, более старый REMB уже не описывает канал
+const REMB_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Пороги потерь из loss-based части GCC (draft-ietf-rmcat-gcc)
+const LOSS_DECREASE_THRESHOLD: f32 = 0.1;
+const LOSS_INCREASE_THRESHOLD: f32 = 0.02;
+const INCREASE_FACTOR: f32 = 1.05;
+
+// Из чего получена оценка
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EstimateSource {
+    // REMB подписчика, ограниченный оценкой по потерям
+    Remb,
+    // Только потери из Receiver Report'ов: REMB не приходил или устарел
+    Loss,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct BandwidthStats {
+    // Итоговая оценка канала до подписчика, бит/с
+    pub estimate: u64,
+    // Последний действующий REMB от браузера подписчика
+    pub remb: Option<u64>,
+    // Доля потерянных пакетов из последнего Receiver Report
+    pub loss: f32,
+    pub source: EstimateSource,
+}
+
+// Оценка пропускной способности канала SFU -> подписчик.
+// Transport-cc feedback здесь не используется: SFU не проставляет transport-wide номера
+// в пакетах к подписчику, а delay-based контроллера для такого feedback в webrtc-rs нет.
+// Поэтому канал по задержкам оценивает браузер подписчика и присылает REMB, оценка по потерям
+// из Receiver Report'ов ограничивает ее сверху. Без REMB (браузер не поддерживает goog-remb
+// или REMB перестал приходить) остается только оценка по потерям, она реагирует медленнее.
+#[derive(Debug)]
+pub struct BandwidthEstimator {
+    loss_based: u64,
+    remb: Option<(u64, Instant)>,
+    loss: f32,
+}
+
+impl Default for BandwidthEstimator {
+    fn default() -> Self {
+        BandwidthEstimator {
+            loss_based: INITIAL_BITRATE,
+            remb: None,
+            loss: 0.0,
+        }
+    }
+}
+
+impl BandwidthEstimator {
+    pub fn on_remb(&mut self, bitrate: f32) {
+        self.on_remb_at(bitrate, Instant::now());
+    }
+
+    fn on_remb_at(&mut self, bitrate: f32, now: Instant) {
+        self.remb = Some((bitrate as u64, now));
+        // Без потерь оценка по потерям не должна мешать росту REMB оценки
+        if self.loss < LOSS_INCREASE_THRESHOLD {
+            self.loss_based = self.loss_based.max(bitrate as u64);
+        }
+    }
+
+    // fraction_lost - доля потерь из отчета в формате RFC 3550 (x/256)
+    pub fn on_receiver_report(&mut self, fraction_lost: u8) {
+        self.loss = fraction_lost as f32 / 256.0;
+
+        let estimate = self.loss_based as f32;
+        let estimate = if self.loss > LOSS_DECREASE_THRESHOLD {
+            estimate * (1.0 - 0.5 * self.loss)
+        } else if self.loss < LOSS_INCREASE_THRESHOLD {
+            estimate * INCREASE_FACTOR
+        } else {
+            estimate
+        };
+
+        self.loss_based = (estimate as u64).clamp(MIN_BITRATE, MAX_BITRATE);
+    }
+
+    fn estimate_at(&self, now: Instant) -> u64 {
+        self.remb_at(now)
+            .map_or(self.loss_based, |remb| remb.min(self.loss_based))
+            .max(MIN_BITRATE)
+    }
+
+    // REMB, если он еще действует
+    fn remb_at(&self, now: Instant) -> Option<u64> {
+        self.remb
+            .filter(|(_, at)| now.duration_since(*at) < REMB_TIMEOUT)
+            .map(|(bitrate, _)| bitrate)
+    }
+
+    pub fn stats(&self) -> BandwidthStats {
+        let now = Instant::now();
+        let remb = self.remb_at(now);
+        BandwidthStats {
+            estimate: self.estimate_at(now),
+            remb,
+            loss: self.loss,
+            source: if remb.is_some() {
+                EstimateSource::Remb
+            } else {
+                EstimateSource::Loss
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remb_caps_estimate() {
+        let mut bwe = BandwidthEstimator::default();
+        assert_eq!(bwe.stats().estimate, INITIAL_BITRATE);
+
+        bwe.on_remb(800_000.0);
+        assert_eq!(bwe.stats().estimate, 800_000);
+
+        bwe.on_remb(4_000_000.0);
+        assert_eq!(bwe.stats().estimate, 4_000_000);
+    }
+
+    #[test]
+    fn loss_reduces_estimate() {
+        let mut bwe = BandwidthEstimator::default();
+        bwe.on_remb(4_000_000.0);
+
+        // 25% потерь
+        bwe.on_receiver_report(64);
+        assert_eq!(bwe.stats().estimate, 3_500_000);
+
+        for _ in 0..100 {
+            bwe.on_receiver_report(255);
+        }
+        assert_eq!(bwe.stats().estimate, MIN_BITRATE);
+
+        // без потерь оценка растет, но не выше REMB
+        for _ in 0..100 {
+            bwe.on_receiver_report(0);
+        }
+        assert_eq!(bwe.stats().estimate, 4_000_000);
+    }
+
+    #[test]
+    fn loss_only_without_remb() {
+        let mut bwe = BandwidthEstimator::default();
+        assert_eq!(bwe.stats().source, EstimateSource::Loss);
+
+        // 25% потерь
+        bwe.on_receiver_report(64);
+        assert_eq!(bwe.stats().estimate, 2_187_500);
+
+        // без потерь оценка растет сама, ее ничто не ограничивает сверху
+        for _ in 0..100 {
+            bwe.on_receiver_report(0);
+        }
+        assert_eq!(bwe.stats().estimate, MAX_BITRATE);
+        assert_eq!(bwe.stats().remb, None);
+    }
+
+    #[test]
+    fn stale_remb_falls_back_to_loss() {
+        let mut bwe = BandwidthEstimator::default();
+        let start = Instant::now();
+        bwe.on_remb_at(800_000.0, start);
+        assert_eq!(bwe.estimate_at(start), 800_000);
+        assert_eq!(bwe.stats().source, EstimateSource::Remb);
+
+        // REMB перестал приходить: оценка снова по потерям
+        let later = start + REMB_TIMEOUT;
+        assert_eq!(bwe.remb_at(later), None);
+        assert_eq!(bwe.estimate_at(later), INITIAL_BITRATE);
+
+        // новый REMB снова ограничивает оценку
+        bwe.on_remb_at(600_000.0, later);
+        assert_eq!(bwe.estimate_at(later), 600_000);
+    }
+}
@@ -0,0 +1,220 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use thiserror::Error;
+use tokio::time::Instant;
+
+// Метка data channel, который клиент открывает для чата
+pub const CHAT_LABEL: &str = "chat";
+
+// Ограничение на размер одного сообщения data channel, байт
+const MAX_MESSAGE_SIZE: usize = 4096;
+const MAX_TEXT_LENGTH: usize = 1000;
+const MAX_EMOJI_LENGTH: usize = 32;
+
+// Token bucket: всплеск до RATE_BURST сообщений, дальше RATE_PER_SECOND в секунду
+const RATE_BURST: f64 = 10.0;
+const RATE_PER_SECOND: f64 = 2.0;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ChatError {
+    #[error("Message is too large")]
+    TooLarge,
+    #[error("Too many messages")]
+    RateLimited,
+    #[error("Invalid message: {0}")]
+    Invalid(String),
+}
+
+// Что клиент может отправить в чат комнаты
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChatPayload {
+    Message { text: String },
+    Reaction { emoji: String },
+    RaiseHand { raised: bool },
+}
+
+impl ChatPayload {
+    pub fn parse(data: &[u8]) -> Result<Self, ChatError> {
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(ChatError::TooLarge);
+        }
+
+        let payload: ChatPayload =
+            serde_json::from_slice(data).map_err(|e| ChatError::Invalid(e.to_string()))?;
+
+        match &payload {
+            ChatPayload::Message { text } if text.trim().is_empty() => {
+                Err(ChatError::Invalid("empty message".to_string()))
+            }
+            ChatPayload::Message { text } if text.chars().count() > MAX_TEXT_LENGTH => {
+                Err(ChatError::TooLarge)
+            }
+            ChatPayload::Reaction { emoji }
+                if emoji.is_empty() || emoji.chars().count() > MAX_EMOJI_LENGTH =>
+            {
+                Err(ChatError::Invalid("invalid emoji".to_string()))
+            }
+            _ => Ok(payload),
+        }
+    }
+
+    // В историю для опоздавших попадают только текстовые сообщения
+    pub fn is_persistent(&self) -> bool {
+        matches!(self, ChatPayload::Message { .. })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatEvent {
+    pub from: String,
+    // unix время в миллисекундах
+    pub sent_at: i64,
+    #[serde(flatten)]
+    pub payload: ChatPayload,
+}
+
+// Что SFU отправляет клиенту в data channel
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatServerMessage<'a> {
+    Event(&'a ChatEvent),
+    History { events: Vec<ChatEvent> },
+    Error { message: String },
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter {
+            tokens: RATE_BURST,
+            updated: Instant::now(),
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * RATE_PER_SECOND).min(RATE_BURST);
+        self.updated = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+// Последние сообщения комнаты для опоздавших участников
+#[derive(Debug, Default)]
+pub struct ChatHistory {
+    events: VecDeque<ChatEvent>,
+}
+
+impl ChatHistory {
+    pub fn push(&mut self, event: ChatEvent, capacity: usize) {
+        if capacity == 0 || !event.payload.is_persistent() {
+            return;
+        }
+
+        self.events.push_back(event);
+        while self.events.len() > capacity {
+            self.events.pop_front();
+        }
+    }
+
+    pub fn events(&self) -> Vec<ChatEvent> {
+        self.events.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    #[test]
+    fn parse_payload() {
+        assert_eq!(
+            ChatPayload::parse(br#"{"kind": "message", "text": "hello"}"#),
+            Ok(ChatPayload::Message {
+                text: "hello".to_string()
+            })
+        );
+        assert_eq!(
+            ChatPayload::parse(br#"{"kind": "raise_hand", "raised": true}"#),
+            Ok(ChatPayload::RaiseHand { raised: true })
+        );
+        assert!(ChatPayload::parse(br#"{"kind": "message", "text": "  "}"#).is_err());
+        assert!(ChatPayload::parse(br#"{"kind": "unknown"}"#).is_err());
+
+        let long = format!(r#"{{"kind": "message", "text": "{}"}}"#, "a".repeat(5000));
+        assert_eq!(
+            ChatPayload::parse(long.as_bytes()),
+            Err(ChatError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        for _ in 0..RATE_BURST as usize {
+            assert!(limiter.try_acquire(now));
+        }
+        assert!(!limiter.try_acquire(now));
+        assert!(limiter.try_acquire(now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn history_keeps_last_messages() {
+        let mut history = ChatHistory::default();
+        for i in 0..5 {
+            history.push(
+                ChatEvent {
+                    from: "a".to_string(),
+                    sent_at: i,
+                    payload: ChatPayload::Message {
+                        text: i.to_string(),
+                    },
+                },
+                3,
+            );
+        }
+        history.push(
+            ChatEvent {
+                from: "a".to_string(),
+                sent_at: 5,
+                payload: ChatPayload::RaiseHand { raised: true },
+            },
+            3,
+        );
+
+        let events = history.events();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].sent_at, 2);
+    }
+
+    #[test]
+    fn serialize_event() {
+        let event = ChatEvent {
+            from: "42".to_string(),
+            sent_at: 1,
+            payload: ChatPayload::Reaction {
+                emoji: "👍".to_string(),
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&ChatServerMessage::Event(&event)).unwrap(),
+            r#"{"type":"event","from":"42","sent_at":1,"kind":"reaction","emoji":"👍"}"#
+        );
+    }
+}
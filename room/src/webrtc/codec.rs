@@ -0,0 +1,238 @@
+use clap::ValueEnum;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::{
+    MediaEngine, MIME_TYPE_AV1, MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_VP8, MIME_TYPE_VP9,
+};
+use webrtc::api::{APIBuilder, API};
+use webrtc::interceptor::registry::Registry;
+use webrtc::rtp_transceiver::rtp_codec::{
+    RTCRtpCodecCapability, RTCRtpCodecParameters, RTCRtpHeaderExtensionCapability, RTPCodecType,
+};
+use webrtc::rtp_transceiver::RTCPFeedback;
+use webrtc::sdp::extmap::{AUDIO_LEVEL_URI, SDES_MID_URI, SDES_RTP_STREAM_ID_URI};
+
+const SDES_REPAIRED_RTP_STREAM_ID_URI: &str =
+    "urn:ietf:params:rtp-hdrext:sdes:repaired-rtp-stream-id";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum VideoCodec {
+    Vp8,
+    Vp9,
+    H264,
+    Av1,
+}
+
+impl VideoCodec {
+    // mime, fmtp и payload type, которые кодек регистрирует в MediaEngine
+    fn payloads(self) -> &'static [(&'static str, &'static str, u8)] {
+        match self {
+            VideoCodec::Vp8 => &[(MIME_TYPE_VP8, "", 96)],
+            VideoCodec::Vp9 => &[
+                (MIME_TYPE_VP9, "profile-id=0", 98),
+                (MIME_TYPE_VP9, "profile-id=1", 100),
+            ],
+            VideoCodec::H264 => &[
+                (
+                    MIME_TYPE_H264,
+                    "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f",
+                    102,
+                ),
+                (
+                    MIME_TYPE_H264,
+                    "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f",
+                    125,
+                ),
+                (
+                    MIME_TYPE_H264,
+                    "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=640032",
+                    123,
+                ),
+            ],
+            VideoCodec::Av1 => &[(MIME_TYPE_AV1, "profile-id=0", 41)],
+        }
+    }
+}
+
+// Набор кодеков, которые SFU согласует с браузерами
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CodecPolicy {
+    // Разрешенные видеокодеки в порядке предпочтения
+    pub video: Vec<VideoCodec>,
+    pub opus_fec: bool,
+    pub opus_dtx: bool,
+}
+
+impl CodecPolicy {
+    fn opus_fmtp(&self) -> String {
+        let mut fmtp = vec!["minptime=10"];
+        if self.opus_fec {
+            fmtp.push("useinbandfec=1");
+        }
+        if self.opus_dtx {
+            fmtp.push("usedtx=1");
+        }
+        fmtp.join(";")
+    }
+
+    fn opus(&self) -> RTCRtpCodecCapability {
+        RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_OPUS.to_owned(),
+            clock_rate: 48000,
+            channels: 2,
+            sdp_fmtp_line: self.opus_fmtp(),
+            rtcp_feedback: vec![],
+        }
+    }
+
+    // Самый предпочтительный кодек вида медиа, его поддерживает любой клиент развертывания
+    pub fn preferred(&self, kind: RTPCodecType) -> RTCRtpCodecCapability {
+        let video = self.video.first().copied().unwrap_or(VideoCodec::Vp8);
+        match kind {
+            RTPCodecType::Audio => self.opus(),
+            _ => {
+                let (mime_type, fmtp, _) = video.payloads()[0];
+                video_capability(mime_type, fmtp)
+            }
+        }
+    }
+
+    fn register(&self, m: &mut MediaEngine) -> webrtc::error::Result<()> {
+        m.register_codec(
+            RTCRtpCodecParameters {
+                capability: self.opus(),
+                payload_type: 111,
+                ..Default::default()
+            },
+            RTPCodecType::Audio,
+        )?;
+
+        // payload type совпадают с MediaEngine::register_default_codecs
+        for codec in &self.video {
+            for (mime_type, fmtp, payload_type) in codec.payloads() {
+                m.register_codec(
+                    RTCRtpCodecParameters {
+                        capability: video_capability(mime_type, fmtp),
+                        payload_type: *payload_type,
+                        ..Default::default()
+                    },
+                    RTPCodecType::Video,
+                )?;
+            }
+        }
+
+        // rid нужен для приема simulcast: браузер отправляет слои одного трека с разными rid
+        for uri in [
+            SDES_MID_URI,
+            SDES_RTP_STREAM_ID_URI,
+            SDES_REPAIRED_RTP_STREAM_ID_URI,
+        ] {
+            m.register_header_extension(
+                RTCRtpHeaderExtensionCapability {
+                    uri: uri.to_owned(),
+                },
+                RTPCodecType::Video,
+                None,
+            )?;
+        }
+
+        // Уровень звука в каждом аудио пакете, по нему определяется говорящий
+        m.register_header_extension(
+            RTCRtpHeaderExtensionCapability {
+                uri: AUDIO_LEVEL_URI.to_owned(),
+            },
+            RTPCodecType::Audio,
+            None,
+        )?;
+
+        Ok(())
+    }
+
+    // API создается один раз на политику и переиспользуется для всех peer connection
+    pub fn build_api(&self) -> webrtc::error::Result<API> {
+        let mut m = MediaEngine::default();
+        self.register(&mut m)?;
+        let mut registry = Registry::new();
+        registry = register_default_interceptors(registry, &mut m)?;
+
+        Ok(APIBuilder::new()
+            .with_media_engine(m)
+            .with_interceptor_registry(registry)
+            .build())
+    }
+}
+
+fn video_capability(mime_type: &str, fmtp: &str) -> RTCRtpCodecCapability {
+    RTCRtpCodecCapability {
+        mime_type: mime_type.to_owned(),
+        clock_rate: 90000,
+        channels: 0,
+        sdp_fmtp_line: fmtp.to_owned(),
+        rtcp_feedback: video_rtcp_feedback(),
+    }
+}
+
+fn video_rtcp_feedback() -> Vec<RTCPFeedback> {
+    [
+        ("goog-remb", ""),
+        ("ccm", "fir"),
+        ("nack", ""),
+        ("nack", "pli"),
+    ]
+    .into_iter()
+    .map(|(typ, parameter)| RTCPFeedback {
+        typ: typ.to_owned(),
+        parameter: parameter.to_owned(),
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opus_fmtp() {
+        let mut policy = CodecPolicy {
+            video: vec![],
+            opus_fec: true,
+            opus_dtx: false,
+        };
+        assert_eq!(policy.opus_fmtp(), "minptime=10;useinbandfec=1");
+
+        policy.opus_fec = false;
+        policy.opus_dtx = true;
+        assert_eq!(policy.opus_fmtp(), "minptime=10;usedtx=1");
+    }
+
+    #[test]
+    fn build_api_with_all_codecs() {
+        let policy = CodecPolicy {
+            video: vec![
+                VideoCodec::Vp8,
+                VideoCodec::Vp9,
+                VideoCodec::H264,
+                VideoCodec::Av1,
+            ],
+            opus_fec: true,
+            opus_dtx: true,
+        };
+        assert!(policy.build_api().is_ok());
+    }
+
+    #[test]
+    fn preferred_codec() {
+        let policy = CodecPolicy {
+            video: vec![VideoCodec::H264, VideoCodec::Vp8],
+            opus_fec: false,
+            opus_dtx: false,
+        };
+        assert_eq!(
+            policy.preferred(RTPCodecType::Audio).mime_type,
+            MIME_TYPE_OPUS
+        );
+        assert_eq!(
+            policy.preferred(RTPCodecType::Video).mime_type,
+            MIME_TYPE_H264
+        );
+    }
+}
@@ -0,0 +1,17 @@
+use crate::webrtc::codec::CodecPolicy;
+use crate::webrtc::ice::IceConfig;
+use tokio::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct SfuConfig {
+    // Вместимость комнат, которые создаются при первом подключении участника
+    pub room_capacity: Option<usize>,
+    // Сколько ждать первого участника, прежде чем удалить пустую комнату
+    pub empty_room_ttl: Duration,
+    pub ice: IceConfig,
+    pub codecs: CodecPolicy,
+    // Сколько последних сообщений чата показывать опоздавшим, 0 - не хранить
+    pub chat_history: usize,
+    // Сколько участник, потерявший соединение, остается в комнате в ожидании ICE restart
+    pub reconnect_window: Duration,
+}
@@ -0,0 +1,620 @@
+use crate::webrtc::bwe::MIN_BITRATE;
+use crate::webrtc::metrics::{kind_label, metrics};
+use crate::webrtc::recording::{Recording, TrackSink};
+use crate::webrtc::room::Room;
+use crate::webrtc::sfu::Participant;
+use crate::webrtc::simulcast::{is_keyframe, select_layer, LayerQuality, LayerSelector};
+use crate::webrtc::speaker::AudioLevel;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Weak};
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::{Duration, Instant};
+use webrtc::rtcp::packet::Packet;
+use webrtc::rtcp::payload_feedbacks::full_intra_request::{FirEntry, FullIntraRequest};
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtcp::payload_feedbacks::receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate;
+use webrtc::rtcp::receiver_report::ReceiverReport;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::sdp::extmap::AUDIO_LEVEL_URI;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::TrackLocalWriter;
+use webrtc::track::track_remote::TrackRemote;
+use webrtc::Error;
+
+// Издатель получает не больше одного запроса ключевого кадра за этот интервал,
+// сколько бы подписчиков его ни просили
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+// Окно, за которое считается битрейт слоя. Слой без пакетов дольше окна считается выключенным.
+const BITRATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+pub enum KeyframeRequest {
+    Pli,
+    Fir,
+}
+
+struct BitrateMeter {
+    window_start: Instant,
+    bytes: usize,
+    bitrate: u64,
+    last_packet: Instant,
+}
+
+impl BitrateMeter {
+    fn new() -> Self {
+        let now = Instant::now();
+        BitrateMeter {
+            window_start: now,
+            bytes: 0,
+            bitrate: 0,
+            last_packet: now,
+        }
+    }
+
+    fn record(&mut self, bytes: usize) {
+        let now = Instant::now();
+        self.last_packet = now;
+        self.bytes += bytes;
+
+        let elapsed = now - self.window_start;
+        if elapsed >= BITRATE_WINDOW {
+            self.bitrate = (self.bytes as f64 * 8.0 / elapsed.as_secs_f64()) as u64;
+            self.bytes = 0;
+            self.window_start = now;
+        }
+    }
+
+    fn bitrate(&self) -> u64 {
+        if self.last_packet.elapsed() > BITRATE_WINDOW {
+            return 0;
+        }
+        if self.bitrate > 0 {
+            return self.bitrate;
+        }
+
+        // первое окно еще не закончилось
+        let elapsed = self.window_start.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            (self.bytes as f64 * 8.0 / elapsed) as u64
+        } else {
+            0
+        }
+    }
+}
+
+// Одна кодировка трека. Без simulcast у трека единственный слой с пустым rid.
+struct Layer {
+    rid: String,
+    track: Arc<TrackRemote>,
+    meter: Mutex<BitrateMeter>,
+    last_keyframe_request: Mutex<Option<Instant>>,
+    // Запрос, пришедший раньше KEYFRAME_REQUEST_INTERVAL, отложен до конца интервала
+    keyframe_deferred: AtomicBool,
+}
+
+struct Subscriber {
+    track: Arc<TrackLocalStaticRTP>,
+    selector: Mutex<LayerSelector>,
+    // Подписчик сам попросил не присылать трек, например скрыв плитку участника
+    paused: AtomicBool,
+}
+
+// Запись трека получает лучший слой, как подписчик с неограниченным каналом
+struct Recorder {
+    sink: TrackSink,
+    selector: LayerSelector,
+}
+
+// Пересылка одного опубликованного трека всем подписчикам.
+// Каждый слой (TrackRemote) читает ровно одна задача, пакет копируется подписчикам, выбравшим этот слой.
+pub struct TrackForwarder {
+    publisher_id: String,
+    publisher: Weak<Participant>,
+    room: Weak<Room>,
+    // Первый пришедший слой, по нему определяются id, тип и кодек трека
+    track: Arc<TrackRemote>,
+    layers: RwLock<HashMap<String, Arc<Layer>>>,
+    subscribers: RwLock<HashMap<String, Arc<Subscriber>>>,
+    recorder: Mutex<Option<Recorder>>,
+    fir_sequence: AtomicU8,
+    // Издатель выключил трек (камеру или микрофон)
+    muted: AtomicBool,
+}
+
+impl TrackForwarder {
+    // Запускает задачу чтения, она живет пока издатель присылает пакеты
+    pub fn spawn(
+        publisher: &Arc<Participant>,
+        room: &Arc<Room>,
+        track: Arc<TrackRemote>,
+    ) -> Arc<Self> {
+        let forwarder = Arc::new(TrackForwarder {
+            publisher_id: publisher.session_id.clone(),
+            publisher: Arc::downgrade(publisher),
+            room: Arc::downgrade(room),
+            track: Arc::clone(&track),
+            layers: Default::default(),
+            subscribers: Default::default(),
+            recorder: Default::default(),
+            fir_sequence: Default::default(),
+            muted: Default::default(),
+        });
+
+        forwarder.add_layer(track);
+
+        forwarder
+    }
+
+    // Очередной simulcast слой того же трека
+    pub fn add_layer(self: &Arc<Self>, track: Arc<TrackRemote>) {
+        let layer = Arc::new(Layer {
+            rid: track.rid().to_string(),
+            track,
+            meter: Mutex::new(BitrateMeter::new()),
+            last_keyframe_request: Default::default(),
+            keyframe_deferred: Default::default(),
+        });
+
+        let forwarder = Arc::clone(self);
+        tokio::spawn(async move {
+            forwarder
+                .layers
+                .write()
+                .await
+                .insert(layer.rid.clone(), Arc::clone(&layer));
+            info!(track:? = layer.track.id(), rid:? = layer.rid; "Track layer added");
+
+            forwarder.run(layer).await;
+        });
+    }
+
+    // session_id участника, опубликовавшего трек
+    pub fn publisher(&self) -> &str {
+        &self.publisher_id
+    }
+
+    pub fn track(&self) -> &Arc<TrackRemote> {
+        &self.track
+    }
+
+    pub async fn subscribe(&self, session_id: String, local_track: Arc<TrackLocalStaticRTP>) {
+        let subscriber = Subscriber {
+            track: local_track,
+            selector: Default::default(),
+            paused: Default::default(),
+        };
+        self.subscribers
+            .write()
+            .await
+            .insert(session_id, Arc::new(subscriber));
+    }
+
+    pub async fn unsubscribe(&self, session_id: &str) -> Option<Arc<TrackLocalStaticRTP>> {
+        self.subscribers
+            .write()
+            .await
+            .remove(session_id)
+            .map(|subscriber| Arc::clone(&subscriber.track))
+    }
+
+    pub async fn subscriber_ids(&self) -> Vec<String> {
+        self.subscribers.read().await.keys().cloned().collect()
+    }
+
+    // Подписчики, которым трек сейчас действительно пересылается
+    pub async fn active_subscriber_ids(&self) -> Vec<String> {
+        if self.is_muted() {
+            return vec![];
+        }
+
+        self.subscribers
+            .read()
+            .await
+            .iter()
+            .filter(|(_, subscriber)| !subscriber.paused.load(Ordering::Relaxed))
+            .map(|(session_id, _)| session_id.clone())
+            .collect()
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    // Возвращает false, если состояние не изменилось
+    pub fn set_muted(&self, muted: bool) -> bool {
+        self.muted.swap(muted, Ordering::Relaxed) != muted
+    }
+
+    pub async fn set_paused(&self, session_id: &str, paused: bool) {
+        if let Some(subscriber) = self.subscribers.read().await.get(session_id) {
+            subscriber.paused.store(paused, Ordering::Relaxed);
+        }
+    }
+
+    pub async fn start_recording(&self, recording: &Arc<Recording>) {
+        let mut recorder = self.recorder.lock().await;
+        if recorder.is_some() {
+            return;
+        }
+
+        let mime_type = self.track.codec().capability.mime_type;
+        *recorder = recording
+            .add_track(&self.publisher_id, &self.track.id(), &mime_type)
+            .map(|sink| Recorder {
+                sink,
+                selector: Default::default(),
+            });
+    }
+
+    // Закрывает очередь записи, файл дописывается в фоне
+    pub async fn stop_recording(&self) {
+        self.recorder.lock().await.take();
+    }
+
+    // Качество simulcast слоя для подписчика, переключение произойдет на ближайшем ключевом кадре
+    pub async fn set_quality(&self, session_id: &str, quality: LayerQuality) {
+        if let Some(subscriber) = self.subscribers.read().await.get(session_id) {
+            subscriber.selector.lock().await.quality = quality;
+        }
+    }
+
+    // Сколько бит/с можно отдавать подписчику по этому треку
+    pub async fn set_budget(&self, session_id: &str, budget: u64) {
+        if let Some(subscriber) = self.subscribers.read().await.get(session_id) {
+            subscriber.selector.lock().await.budget = Some(budget);
+        }
+    }
+
+    // Ограничивает битрейт издателя: больше, чем может принять лучший подписчик, слать незачем.
+    // Для simulcast к лимиту добавляются нижние слои, их тоже кто-то может получать.
+    pub async fn send_remb(&self, budget: u64) {
+        let Some(publisher) = self.publisher.upgrade() else {
+            return;
+        };
+
+        let bitrates = self.bitrates().await;
+        let top = select_layer(&bitrates, LayerQuality::High, Some(budget))
+            .and_then(|rid| bitrates.iter().find(|(r, _)| r == rid))
+            .map_or(0, |(_, bitrate)| *bitrate);
+        let lower = bitrates
+            .iter()
+            .map(|(_, bitrate)| *bitrate)
+            .filter(|bitrate| *bitrate < top)
+            .sum::<u64>();
+
+        let ssrcs = self
+            .layers
+            .read()
+            .await
+            .values()
+            .map(|layer| layer.track.ssrc())
+            .collect();
+        let remb = ReceiverEstimatedMaximumBitrate {
+            sender_ssrc: 0,
+            bitrate: budget.max(MIN_BITRATE).saturating_add(lower) as f32,
+            ssrcs,
+        };
+
+        if let Err(e) = publisher.pc.write_rtcp(&[Box::new(remb)]).await {
+            warn!(err:? = e, user:? = self.publisher_id; "Could not send REMB");
+        }
+    }
+
+    async fn bitrates(&self) -> Vec<(String, u64)> {
+        let mut bitrates = vec![];
+        for layer in self.layers.read().await.values() {
+            bitrates.push((layer.rid.clone(), layer.meter.lock().await.bitrate()));
+        }
+        bitrates
+    }
+
+    // Запрашивает ключевой кадр слоя, который получает (или вот-вот начнет получать) подписчик
+    pub async fn request_keyframe(self: &Arc<Self>, session_id: &str, request: KeyframeRequest) {
+        let Some(subscriber) = self.subscribers.read().await.get(session_id).cloned() else {
+            return;
+        };
+
+        let (quality, budget) = {
+            let selector = subscriber.selector.lock().await;
+            if let Some(rid) = selector.current() {
+                let rid = rid.to_string();
+                drop(selector);
+                self.request_layer_keyframe(&rid, request).await;
+                return;
+            }
+            (selector.quality, selector.budget)
+        };
+
+        let bitrates = self.bitrates().await;
+        if let Some(rid) = select_layer(&bitrates, quality, budget) {
+            self.request_layer_keyframe(rid, request).await;
+        }
+    }
+
+    // Запрашивает у издателя ключевой кадр слоя с ограничением частоты. Частые запросы
+    // не теряются: один из них уходит в конце интервала, например PLI подписчика сразу после
+    // перепереговоров.
+    async fn request_layer_keyframe(self: &Arc<Self>, rid: &str, request: KeyframeRequest) {
+        if self.track.kind() != RTPCodecType::Video {
+            return;
+        }
+
+        let Some(layer) = self.layers.read().await.get(rid).cloned() else {
+            return;
+        };
+
+        {
+            let mut last = layer.last_keyframe_request.lock().await;
+            if let Some(wait) = last
+                .map(|t| KEYFRAME_REQUEST_INTERVAL.saturating_sub(t.elapsed()))
+                .filter(|wait| !wait.is_zero())
+            {
+                if !layer.keyframe_deferred.swap(true, Ordering::Relaxed) {
+                    let this = Arc::clone(self);
+                    let layer = Arc::clone(&layer);
+                    tokio::spawn(async move {
+                        tokio::time::sleep(wait).await;
+                        *layer.last_keyframe_request.lock().await = Some(Instant::now());
+                        layer.keyframe_deferred.store(false, Ordering::Relaxed);
+                        this.send_keyframe_request(&layer, request).await;
+                    });
+                }
+                return;
+            }
+            *last = Some(Instant::now());
+        }
+
+        self.send_keyframe_request(&layer, request).await;
+    }
+
+    async fn send_keyframe_request(&self, layer: &Layer, request: KeyframeRequest) {
+        let Some(publisher) = self.publisher.upgrade() else {
+            return;
+        };
+
+        let media_ssrc = layer.track.ssrc();
+        let (packet, label): (Box<dyn Packet + Send + Sync>, _) = match request {
+            KeyframeRequest::Pli => (
+                Box::new(PictureLossIndication {
+                    sender_ssrc: 0,
+                    media_ssrc,
+                }),
+                "pli",
+            ),
+            KeyframeRequest::Fir => (
+                Box::new(FullIntraRequest {
+                    sender_ssrc: 0,
+                    media_ssrc,
+                    fir: vec![FirEntry {
+                        ssrc: media_ssrc,
+                        sequence_number: self.fir_sequence.fetch_add(1, Ordering::Relaxed),
+                    }],
+                }),
+                "fir",
+            ),
+        };
+
+        match publisher.pc.write_rtcp(&[packet]).await {
+            Ok(_) => metrics()
+                .keyframe_requests
+                .with_label_values(&[label])
+                .inc(),
+            Err(e) => warn!(err:? = e, user:? = self.publisher_id; "Could not request keyframe"),
+        }
+    }
+
+    // Читает RTCP подписчика и пересылает его запросы ключевого кадра издателю.
+    // Чтение RTCP также нужно, чтобы отрабатывали интерсепторы (NACK и пр.)
+    // REMB и Receiver Report'ы подписчика идут в оценку его канала.
+    pub fn spawn_rtcp_reader(
+        self: &Arc<Self>,
+        subscriber: &Arc<Participant>,
+        sender: Arc<RTCRtpSender>,
+    ) {
+        let forwarder = Arc::downgrade(self);
+        let session_id = subscriber.session_id.clone();
+        let subscriber = Arc::downgrade(subscriber);
+        tokio::spawn(async move {
+            // Составной RTCP пакет приходит каждому sender'у, которого он упоминает,
+            // поэтому учитываются только отчеты и запросы о своих SSRC
+            let ssrcs = sender
+                .get_parameters()
+                .await
+                .encodings
+                .iter()
+                .flat_map(|encoding| [encoding.ssrc, encoding.rtx.ssrc])
+                .filter(|ssrc| *ssrc != 0)
+                .collect::<Vec<_>>();
+
+            let mut bound = false;
+            while let Ok((packets, _)) = sender.read_rtcp().await {
+                let Some(forwarder) = forwarder.upgrade() else {
+                    break;
+                };
+
+                // Первый RTCP подписчика: sender привязан после перепереговоров, и ключевой кадр
+                // уже можно декодировать. Запрошенный раньше кадр подписчик бы пропустил.
+                if !bound {
+                    bound = true;
+                    forwarder
+                        .request_keyframe(&session_id, KeyframeRequest::Pli)
+                        .await;
+                }
+
+                for packet in packets {
+                    let packet = packet.as_any();
+                    if let Some(remb) = packet.downcast_ref::<ReceiverEstimatedMaximumBitrate>() {
+                        if let Some(subscriber) = subscriber.upgrade() {
+                            subscriber.bandwidth.lock().await.on_remb(remb.bitrate);
+                        }
+                    } else if let Some(rr) = packet.downcast_ref::<ReceiverReport>() {
+                        if let Some(subscriber) = subscriber.upgrade() {
+                            let mut bandwidth = subscriber.bandwidth.lock().await;
+                            for report in rr.reports.iter().filter(|r| ssrcs.contains(&r.ssrc)) {
+                                bandwidth.on_receiver_report(report.fraction_lost);
+                            }
+                        }
+                    } else if let Some(pli) = packet.downcast_ref::<PictureLossIndication>() {
+                        if ssrcs.contains(&pli.media_ssrc) {
+                            forwarder
+                                .request_keyframe(&session_id, KeyframeRequest::Pli)
+                                .await;
+                        }
+                    } else if let Some(fir) = packet.downcast_ref::<FullIntraRequest>() {
+                        if fir.fir.iter().any(|entry| ssrcs.contains(&entry.ssrc)) {
+                            forwarder
+                                .request_keyframe(&session_id, KeyframeRequest::Fir)
+                                .await;
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    async fn run(self: &Arc<Self>, layer: Arc<Layer>) {
+        let mime_type = layer.track.codec().capability.mime_type;
+        let audio_level_id = layer
+            .track
+            .params()
+            .header_extensions
+            .iter()
+            .find(|extension| extension.uri == AUDIO_LEVEL_URI)
+            .map(|extension| extension.id as u8);
+        let kind = kind_label(layer.track.kind());
+        let forwarded_packets = metrics().forwarded_packets.with_label_values(&[kind]);
+        let forwarded_bytes = metrics().forwarded_bytes.with_label_values(&[kind]);
+
+        while let Ok((rtp, _)) = layer.track.read_rtp().await {
+            layer.meter.lock().await.record(rtp.payload.len());
+            let muted = self.is_muted();
+
+            // Выключенный микрофон не делает участника говорящим
+            if let Some(level) = audio_level_id
+                .filter(|_| !muted)
+                .and_then(|id| rtp.header.get_extension(id))
+                .and_then(|payload| AudioLevel::parse(&payload))
+            {
+                if let Some(room) = self.room.upgrade() {
+                    room.speakers
+                        .lock()
+                        .await
+                        .observe(&self.publisher_id, level);
+                }
+            }
+
+            // Без simulcast переключаться не на что, пересылать можно с любого пакета
+            let keyframe = layer.rid.is_empty() || is_keyframe(&mime_type, &rtp.payload);
+            let bitrates = self.bitrates().await;
+
+            let mut failed = vec![];
+            let mut keyframe_requests = vec![];
+
+            for (session_id, subscriber) in self.subscribers.read().await.iter() {
+                let mut packet = rtp.clone();
+                {
+                    let mut selector = subscriber.selector.lock().await;
+                    // После паузы слой выбирается заново: с ключевого кадра и с непрерывными номерами пакетов
+                    if muted || subscriber.paused.load(Ordering::Relaxed) {
+                        selector.pause();
+                        continue;
+                    }
+                    match select_layer(&bitrates, selector.quality, selector.budget) {
+                        Some(target) => {
+                            if selector.is_paused() {
+                                info!(user:? = session_id, track:? = self.track.id(); "Video resumed");
+                            }
+                            if selector.retarget(target)
+                                && !keyframe_requests.iter().any(|r| r == target)
+                            {
+                                keyframe_requests.push(target.to_string());
+                            }
+                        }
+                        None => {
+                            if selector.pause() {
+                                info!(user:? = session_id, track:? = self.track.id(), budget:? = selector.budget; "Video paused, not enough bandwidth");
+                            }
+                        }
+                    }
+                    if !selector.accept(&layer.rid, keyframe, &mut packet.header) {
+                        continue;
+                    }
+                }
+
+                match subscriber.track.write_rtp(&packet).await {
+                    Ok(bytes) => {
+                        forwarded_packets.inc();
+                        forwarded_bytes.inc_by(bytes as u64);
+                    }
+                    // ErrClosedPipe - у подписчика еще (или уже) нет активного RTCRtpSender
+                    Err(Error::ErrClosedPipe) => {}
+                    Err(err) => {
+                        warn!(err:? = err, user:? = session_id; "output track write_rtp got error");
+                        failed.push(session_id.clone());
+                    }
+                }
+            }
+
+            {
+                let mut recorder = self.recorder.lock().await;
+                if let Some(current) = recorder.as_mut() {
+                    let mut packet = rtp.clone();
+                    // Выключенный трек не записывается, после включения запись продолжится с ключевого кадра
+                    if muted {
+                        current.selector.pause();
+                    } else if let Some(target) = select_layer(&bitrates, LayerQuality::High, None) {
+                        if current.selector.retarget(target)
+                            && !keyframe_requests.iter().any(|r| r == target)
+                        {
+                            keyframe_requests.push(target.to_string());
+                        }
+                    }
+                    if current
+                        .selector
+                        .accept(&layer.rid, keyframe, &mut packet.header)
+                        && !current.sink.write(&packet)
+                    {
+                        recorder.take();
+                    }
+                }
+            }
+
+            if !failed.is_empty() {
+                let mut subscribers = self.subscribers.write().await;
+                for session_id in failed {
+                    subscribers.remove(&session_id);
+                }
+            }
+
+            for rid in keyframe_requests {
+                self.request_layer_keyframe(&rid, KeyframeRequest::Pli)
+                    .await;
+            }
+        }
+
+        let remaining = {
+            let mut layers = self.layers.write().await;
+            layers.remove(&layer.rid);
+            layers.len()
+        };
+
+        if remaining > 0 {
+            for subscriber in self.subscribers.read().await.values() {
+                subscriber.selector.lock().await.drop_layer(&layer.rid);
+            }
+            if let Some(recorder) = self.recorder.lock().await.as_mut() {
+                recorder.selector.drop_layer(&layer.rid);
+            }
+            info!(track:? = self.track.id(), rid:? = layer.rid; "Track layer finished");
+            return;
+        }
+
+        self.subscribers.write().await.clear();
+        self.stop_recording().await;
+        info!(track:? = self.track.id(); "Track forwarding finished");
+    }
+}
@@ -0,0 +1,120 @@
+use base64::prelude::{Engine, BASE64_STANDARD};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Duration;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+
+#[derive(Debug, Clone)]
+pub enum TurnCredentials {
+    Static {
+        username: String,
+        credential: String,
+    },
+    // Временные учетные данные по схеме "TURN REST API" (coturn --use-auth-secret):
+    // username = "<unix время истечения>:<пользователь>", credential = base64(hmac-sha1(secret, username))
+    Rest {
+        secret: String,
+        ttl: Duration,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct IceConfig {
+    pub stun_urls: Vec<String>,
+    pub turn_urls: Vec<String>,
+    pub turn_credentials: Option<TurnCredentials>,
+}
+
+impl IceConfig {
+    // Список ICE серверов для пользователя, одинаковый для SFU и для клиента
+    pub fn ice_servers(&self, user: &str) -> Vec<RTCIceServer> {
+        let mut servers = vec![];
+
+        if !self.stun_urls.is_empty() {
+            servers.push(RTCIceServer {
+                urls: self.stun_urls.clone(),
+                ..Default::default()
+            });
+        }
+
+        let credentials = match &self.turn_credentials {
+            Some(TurnCredentials::Static {
+                username,
+                credential,
+            }) => Some((username.clone(), credential.clone())),
+            Some(TurnCredentials::Rest { secret, ttl }) => {
+                let expires_at = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    + *ttl;
+                Some(rest_credentials(secret, user, expires_at.as_secs()))
+            }
+            None => None,
+        };
+
+        // TURN без учетных данных не пройдет валидацию в RTCConfiguration
+        if let (false, Some((username, credential))) = (self.turn_urls.is_empty(), credentials) {
+            servers.push(RTCIceServer {
+                urls: self.turn_urls.clone(),
+                username,
+                credential,
+            });
+        }
+
+        servers
+    }
+}
+
+fn rest_credentials(secret: &str, user: &str, expires_at: u64) -> (String, String) {
+    let username = format!("{}:{}", expires_at, user);
+
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(username.as_bytes());
+    let credential = BASE64_STANDARD.encode(mac.finalize().into_bytes());
+
+    (username, credential)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rest_credentials_hmac_sha1() {
+        let (username, credential) = rest_credentials("north", "42", 1700000000);
+        assert_eq!(username, "1700000000:42");
+        assert_eq!(credential, "DWX3FI7DiPIpadEYQAZsnZBAagI=");
+    }
+
+    #[test]
+    fn turn_without_credentials_is_skipped() {
+        let config = IceConfig {
+            stun_urls: vec!["stun:stun.l.google.com:19302".to_string()],
+            turn_urls: vec!["turn:turn.example.com:3478".to_string()],
+            turn_credentials: None,
+        };
+
+        let servers = config.ice_servers("42");
+        assert_eq!(servers.len(), 1);
+        assert!(servers[0].username.is_empty());
+    }
+
+    #[test]
+    fn static_turn_credentials() {
+        let config = IceConfig {
+            stun_urls: vec![],
+            turn_urls: vec!["turn:turn.example.com:3478".to_string()],
+            turn_credentials: Some(TurnCredentials::Static {
+                username: "user".to_string(),
+                credential: "pass".to_string(),
+            }),
+        };
+
+        let servers = config.ice_servers("42");
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].username, "user");
+        assert_eq!(servers[0].credential, "pass");
+    }
+}
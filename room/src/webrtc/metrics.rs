@@ -0,0 +1,123 @@
+use prometheus::{
+    Encoder, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+
+static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new().expect("Invalid SFU metric definitions"));
+
+// Метрики SFU. Счетчики обновляются по ходу работы, gauges - перед каждым сбором.
+pub struct Metrics {
+    registry: Registry,
+    pub rooms: IntGauge,
+    pub participants: IntGauge,
+    pub tracks: IntGaugeVec,
+    pub ws_sessions: IntGauge,
+    pub forwarded_packets: IntCounterVec,
+    pub forwarded_bytes: IntCounterVec,
+    pub keyframe_requests: IntCounterVec,
+    pub negotiation_failures: IntCounter,
+    pub peer_states: IntCounterVec,
+    pub recording_dropped_packets: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("sfu".to_string()), None)?;
+
+        let rooms = IntGauge::new("rooms", "Open rooms")?;
+        let participants = IntGauge::new("participants", "Connected peers")?;
+        let tracks = IntGaugeVec::new(Opts::new("tracks", "Published tracks"), &["kind"])?;
+        let ws_sessions = IntGauge::new("ws_sessions", "Open websocket sessions")?;
+        let forwarded_packets = IntCounterVec::new(
+            Opts::new("forwarded_packets_total", "RTP packets sent to subscribers"),
+            &["kind"],
+        )?;
+        let forwarded_bytes = IntCounterVec::new(
+            Opts::new("forwarded_bytes_total", "RTP bytes sent to subscribers"),
+            &["kind"],
+        )?;
+        let keyframe_requests = IntCounterVec::new(
+            Opts::new("keyframe_requests_total", "PLI and FIR sent to publishers"),
+            &["type"],
+        )?;
+        let negotiation_failures =
+            IntCounter::new("negotiation_failures_total", "Failed renegotiations")?;
+        let peer_states = IntCounterVec::new(
+            Opts::new(
+                "peer_connection_states_total",
+                "Peer connection state transitions",
+            ),
+            &["state"],
+        )?;
+
+        let recording_dropped_packets = IntCounter::new(
+            "recording_dropped_packets_total",
+            "RTP packets dropped because the recording queue was full",
+        )?;
+
+        registry.register(Box::new(rooms.clone()))?;
+        registry.register(Box::new(participants.clone()))?;
+        registry.register(Box::new(tracks.clone()))?;
+        registry.register(Box::new(ws_sessions.clone()))?;
+        registry.register(Box::new(forwarded_packets.clone()))?;
+        registry.register(Box::new(forwarded_bytes.clone()))?;
+        registry.register(Box::new(keyframe_requests.clone()))?;
+        registry.register(Box::new(negotiation_failures.clone()))?;
+        registry.register(Box::new(peer_states.clone()))?;
+        registry.register(Box::new(recording_dropped_packets.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            rooms,
+            participants,
+            tracks,
+            ws_sessions,
+            forwarded_packets,
+            forwarded_bytes,
+            keyframe_requests,
+            negotiation_failures,
+            peer_states,
+            recording_dropped_packets,
+        })
+    }
+
+    // Текстовый формат Prometheus
+    pub fn encode(&self) -> prometheus::Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+pub fn kind_label(kind: RTPCodecType) -> &'static str {
+    match kind {
+        RTPCodecType::Audio => "audio",
+        RTPCodecType::Video => "video",
+        RTPCodecType::Unspecified => "unspecified",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_metrics() {
+        let metrics = Metrics::new().unwrap();
+        metrics.rooms.set(2);
+        metrics
+            .forwarded_packets
+            .with_label_values(&["video"])
+            .inc_by(10);
+
+        let text = metrics.encode().unwrap();
+        assert!(text.contains("sfu_rooms 2"));
+        assert!(text.contains(r#"sfu_forwarded_packets_total{kind="video"} 10"#));
+    }
+}
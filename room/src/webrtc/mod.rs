@@ -1,2 +1,20 @@
+pub mod admin;
 pub mod axum;
+pub mod bwe;
+pub mod chat;
+pub mod codec;
+pub mod config;
+pub mod forward;
+pub mod ice;
+pub mod metrics;
+pub mod recording;
+pub mod registry;
+pub mod room;
+pub mod roster;
+pub mod session;
 pub mod sfu;
+pub mod simulcast;
+pub mod speaker;
+pub mod viewer;
+pub mod whep;
+pub mod whip;
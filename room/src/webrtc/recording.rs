@@ -0,0 +1,503 @@
+use crate::webrtc::metrics::metrics;
+use anyhow::Result;
+use chrono::{NaiveDateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use std::fs;
+use std::fs::File;
+use std::io::{Seek, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use webrtc::api::media_engine::{MIME_TYPE_OPUS, MIME_TYPE_VP8, MIME_TYPE_VP9};
+use webrtc::media::io::ivf_reader::IVFFileHeader;
+use webrtc::media::io::ivf_writer::IVFWriter;
+use webrtc::media::io::ogg_writer::OggWriter;
+use webrtc::media::io::Writer;
+use webrtc::rtp::codecs::vp8::Vp8Packet;
+use webrtc::rtp::codecs::vp9::Vp9Packet;
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp::packetizer::Depacketizer;
+
+// Сколько пакетов может ждать записи на диск, дальше пакеты отбрасываются
+const TRACK_QUEUE: usize = 1024;
+// Не чаще одного предупреждения об отброшенных пакетах на дорожку
+const DROP_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+const METADATA_FILE: &str = "metadata.json";
+
+#[derive(Error, Debug)]
+pub enum RecordingError {
+    #[error("Recording is not configured")]
+    Disabled,
+    #[error("Not a participant of the room")]
+    NotInRoom,
+    #[error("Room is already being recorded")]
+    AlreadyStarted,
+    #[error("Room is not being recorded")]
+    NotStarted,
+}
+
+pub trait MediaFile: Write + Seek + Send {}
+
+impl<T: Write + Seek + Send> MediaFile for T {}
+
+// Куда сохраняются записи. Методы блокирующие, их вызывают из spawn_blocking.
+pub trait RecordingStorage: Sync + Send {
+    fn create(&self, recording_id: &str, name: &str) -> Result<Box<dyn MediaFile>>;
+    fn save_metadata(&self, metadata: &RecordingMetadata) -> Result<()>;
+}
+
+// Каждая запись в отдельном каталоге <root>/<recording_id>
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> Self {
+        LocalStorage { root }
+    }
+
+    fn dir(&self, recording_id: &str) -> Result<PathBuf> {
+        let dir = self.root.join(recording_id);
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+}
+
+impl RecordingStorage for LocalStorage {
+    fn create(&self, recording_id: &str, name: &str) -> Result<Box<dyn MediaFile>> {
+        let file = File::create(self.dir(recording_id)?.join(name))?;
+        Ok(Box::new(file))
+    }
+
+    fn save_metadata(&self, metadata: &RecordingMetadata) -> Result<()> {
+        let dir = self.dir(&metadata.id)?;
+        // Через временный файл, чтобы не оставить обрезанный json
+        let tmp = dir.join(format!("{METADATA_FILE}.tmp"));
+        fs::write(&tmp, serde_json::to_vec_pretty(metadata)?)?;
+        fs::rename(tmp, dir.join(METADATA_FILE))?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RecordedTrack {
+    pub session_id: String,
+    pub track_id: String,
+    pub mime_type: String,
+    pub file: String,
+    // По времени начала и конца дорожки выравниваются при сведении
+    pub started_at: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RecordingMetadata {
+    pub id: String,
+    pub room_id: String,
+    pub started_by: String,
+    pub started_at: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+    pub participants: Vec<String>,
+    pub tracks: Vec<RecordedTrack>,
+}
+
+// Очередь дорожки общая у forwarder и записи: очередь закрывает тот, кто заканчивает первым
+type TrackSender = Arc<Mutex<Option<mpsc::Sender<Packet>>>>;
+
+// Очередь пакетов одной дорожки записи
+pub struct TrackSink {
+    sender: TrackSender,
+    file: String,
+    // Пакеты, отброшенные из-за переполненной очереди: диск не успевает за потоком
+    dropped: AtomicU64,
+    warned_at: Mutex<Option<Instant>>,
+}
+
+impl TrackSink {
+    fn new(sender: TrackSender, file: String) -> Self {
+        TrackSink {
+            sender,
+            file,
+            dropped: AtomicU64::new(0),
+            warned_at: Mutex::new(None),
+        }
+    }
+
+    // false - запись дорожки завершилась и пакеты больше не нужны
+    pub fn write(&self, packet: &Packet) -> bool {
+        let sender = self.sender.lock().unwrap();
+        let Some(sender) = sender.as_ref() else {
+            return false;
+        };
+        match sender.try_send(packet.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.on_dropped();
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    fn on_dropped(&self) {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics().recording_dropped_packets.inc();
+
+        let mut warned_at = self.warned_at.lock().unwrap();
+        if warned_at.is_none_or(|at| at.elapsed() >= DROP_WARNING_INTERVAL) {
+            *warned_at = Some(Instant::now());
+            warn!(file:? = self.file, dropped:? = dropped; "Recording queue is full, packets are dropped");
+        }
+    }
+}
+
+// Издатель ушел, дорожка дописывается и закрывается
+impl Drop for TrackSink {
+    fn drop(&mut self) {
+        self.sender.lock().unwrap().take();
+    }
+}
+
+// Запись комнаты: Opus каждого участника в Ogg, VP8/VP9 в IVF
+pub struct Recording {
+    pub(crate) id: String,
+    storage: Arc<dyn RecordingStorage>,
+    metadata: Mutex<RecordingMetadata>,
+    // Очереди и задачи записи дорожек, finish дожидается их завершения
+    writers: Mutex<Vec<(TrackSender, JoinHandle<()>)>>,
+}
+
+impl Recording {
+    pub fn new(storage: Arc<dyn RecordingStorage>, room_id: &str, started_by: &str) -> Self {
+        let id = uuid::Uuid::new_v4().to_string();
+        Recording {
+            id: id.clone(),
+            storage,
+            metadata: Mutex::new(RecordingMetadata {
+                id,
+                room_id: room_id.to_string(),
+                started_by: started_by.to_string(),
+                started_at: Utc::now().naive_utc(),
+                ended_at: None,
+                participants: vec![],
+                tracks: vec![],
+            }),
+            writers: Mutex::new(vec![]),
+        }
+    }
+
+    pub async fn save(self: &Arc<Self>) -> Result<()> {
+        let this = Arc::clone(self);
+        tokio::task::spawn_blocking(move || this.save_blocking()).await?
+    }
+
+    fn save_blocking(&self) -> Result<()> {
+        // Под блокировкой, чтобы старый снимок не перезаписал более новый
+        let metadata = self.metadata.lock().unwrap();
+        self.storage.save_metadata(&metadata)
+    }
+
+    // Закрывает очереди дорожек и ждет, пока они допишутся и получат ended_at
+    pub async fn finish(self: &Arc<Self>) -> Result<RecordingMetadata> {
+        let writers = std::mem::take(&mut *self.writers.lock().unwrap());
+        for (sender, _) in &writers {
+            sender.lock().unwrap().take();
+        }
+        for (_, writer) in writers {
+            if let Err(e) = writer.await {
+                warn!(recording:? = self.id, err:? = e; "Track writer failed");
+            }
+        }
+
+        self.metadata.lock().unwrap().ended_at = Some(Utc::now().naive_utc());
+        self.save().await?;
+
+        let metadata = self.metadata.lock().unwrap().clone();
+        info!(recording:? = metadata.id, room:? = metadata.room_id; "Recording finished");
+        Ok(metadata)
+    }
+
+    // Начинает запись дорожки, None - кодек не поддерживается
+    pub fn add_track(
+        self: &Arc<Self>,
+        session_id: &str,
+        track_id: &str,
+        mime_type: &str,
+    ) -> Option<TrackSink> {
+        let extension = if mime_type.eq_ignore_ascii_case(MIME_TYPE_OPUS) {
+            "ogg"
+        } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8)
+            || mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9)
+        {
+            "ivf"
+        } else {
+            warn!(recording:? = self.id, user:? = session_id, mime_type:? = mime_type; "Codec is not supported by recording");
+            return None;
+        };
+
+        let (index, file) = {
+            let mut metadata = self.metadata.lock().unwrap();
+            let index = metadata.tracks.len();
+            let file = format!("{index:02}-{}.{extension}", sanitize(session_id));
+            metadata.tracks.push(RecordedTrack {
+                session_id: session_id.to_string(),
+                track_id: track_id.to_string(),
+                mime_type: mime_type.to_string(),
+                file: file.clone(),
+                started_at: Utc::now().naive_utc(),
+                ended_at: None,
+            });
+            if !metadata.participants.iter().any(|p| p == session_id) {
+                metadata.participants.push(session_id.to_string());
+            }
+            (index, file)
+        };
+
+        let (sender, receiver) = mpsc::channel(TRACK_QUEUE);
+        let sender = Arc::new(Mutex::new(Some(sender)));
+        let this = Arc::clone(self);
+        let mime_type = mime_type.to_string();
+        let name = file.clone();
+        let writer = tokio::task::spawn_blocking(move || {
+            if let Err(e) = this.save_blocking() {
+                warn!(recording:? = this.id, err:? = e; "Could not save recording metadata");
+            }
+
+            if let Err(e) = this.write_track(&name, &mime_type, receiver) {
+                warn!(recording:? = this.id, file:? = name, err:? = e; "Could not record track");
+            }
+
+            this.metadata.lock().unwrap().tracks[index].ended_at = Some(Utc::now().naive_utc());
+            if let Err(e) = this.save_blocking() {
+                warn!(recording:? = this.id, err:? = e; "Could not save recording metadata");
+            }
+        });
+        self.writers
+            .lock()
+            .unwrap()
+            .push((Arc::clone(&sender), writer));
+
+        info!(recording:? = self.id, user:? = session_id, track:? = track_id; "Track recording started");
+        Some(TrackSink::new(sender, file))
+    }
+
+    // Пишет пакеты, пока forwarder не закроет очередь
+    fn write_track(
+        &self,
+        name: &str,
+        mime_type: &str,
+        mut receiver: mpsc::Receiver<Packet>,
+    ) -> Result<()> {
+        let vp9 = mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9);
+        // Видео файл ждет первого ключевого кадра: из него берется размер для заголовка IVF
+        let mut file = Some(self.storage.create(&self.id, name)?);
+        let mut writer: Option<Box<dyn Writer>> = None;
+        if mime_type.eq_ignore_ascii_case(MIME_TYPE_OPUS) {
+            if let Some(file) = file.take() {
+                writer = Some(Box::new(OggWriter::new(file, 48000, 2)?));
+            }
+        }
+
+        let mut last = None;
+        while let Some(packet) = receiver.blocking_recv() {
+            // Писатели рассчитывают на возрастающие метки времени, опоздавшие пакеты пропускаются
+            if !is_newer(packet.header.sequence_number, last) {
+                continue;
+            }
+            last = Some(packet.header.sequence_number);
+
+            // IVFWriter все равно пропускает пакеты до ключевого кадра
+            if file.is_some() {
+                let Some((width, height)) = frame_size(vp9, &packet) else {
+                    continue;
+                };
+                if let Some(file) = file.take() {
+                    writer = Some(ivf_writer(file, vp9, width, height)?);
+                }
+            }
+            let Some(writer) = writer.as_mut() else {
+                continue;
+            };
+
+            // Испорченный пакет не должен обрывать всю дорожку
+            if let Err(e) = writer.write_rtp(&packet) {
+                warn!(recording:? = self.id, file:? = name, err:? = e; "Could not write packet");
+            }
+        }
+
+        // Ключевой кадр так и не пришел: пустой IVF без размера кадра
+        if let Some(file) = file.take() {
+            writer = Some(ivf_writer(file, vp9, 0, 0)?);
+        }
+        if let Some(mut writer) = writer {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+// PTS в IVF - номер кадра
+fn ivf_writer(
+    file: Box<dyn MediaFile>,
+    vp9: bool,
+    width: u16,
+    height: u16,
+) -> Result<Box<dyn Writer>> {
+    let four_cc = if vp9 { *b"VP90" } else { *b"VP80" };
+    let writer = IVFWriter::new(
+        file,
+        &IVFFileHeader {
+            signature: *b"DKIF",
+            version: 0,
+            header_size: 32,
+            four_cc,
+            width,
+            height,
+            timebase_denominator: 30,
+            timebase_numerator: 1,
+            num_frames: 0,
+            unused: 0,
+        },
+    )?;
+    Ok(Box::new(writer))
+}
+
+// Размер кадра из первого пакета ключевого кадра, None - это не начало ключевого кадра
+fn frame_size(vp9: bool, packet: &Packet) -> Option<(u16, u16)> {
+    if vp9 {
+        // VP9 передает размеры в scalability structure, берется старший пространственный слой
+        let mut vp9 = Vp9Packet::default();
+        vp9.depacketize(&packet.payload).ok()?;
+        if vp9.p || !vp9.b || !vp9.v {
+            return None;
+        }
+        return Some((*vp9.width.last()?, *vp9.height.last()?));
+    }
+
+    let mut vp8 = Vp8Packet::default();
+    let frame = vp8.depacketize(&packet.payload).ok()?;
+    // Заголовок ключевого кадра VP8: 3 байта frame tag, start code 9d 01 2a, ширина и высота по 14 бит
+    if vp8.s != 1 || vp8.pid != 0 || frame.len() < 10 || frame[0] & 0x01 != 0 {
+        return None;
+    }
+    if frame[3..6] != [0x9d, 0x01, 0x2a] {
+        return None;
+    }
+    let width = u16::from_le_bytes([frame[6], frame[7]]) & 0x3fff;
+    let height = u16::from_le_bytes([frame[8], frame[9]]) & 0x3fff;
+    Some((width, height))
+}
+
+fn is_newer(sequence_number: u16, last: Option<u16>) -> bool {
+    last.is_none_or(|last| sequence_number != last && sequence_number.wrapping_sub(last) < 0x8000)
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_reordered_packets() {
+        assert!(is_newer(10, None));
+        assert!(is_newer(11, Some(10)));
+        assert!(!is_newer(10, Some(10)));
+        assert!(!is_newer(9, Some(10)));
+        assert!(is_newer(2, Some(65530)));
+    }
+
+    #[test]
+    fn vp8_keyframe_size() {
+        let packet = |payload: Vec<u8>| Packet {
+            payload: payload.into(),
+            ..Default::default()
+        };
+
+        let keyframe = packet(vec![
+            0x10, 0x00, 0x00, 0x00, 0x9d, 0x01, 0x2a, 0x80, 0x02, 0xe0, 0x01,
+        ]);
+        assert_eq!(frame_size(false, &keyframe), Some((640, 480)));
+
+        let interframe = packet(vec![
+            0x10, 0x01, 0x00, 0x00, 0x9d, 0x01, 0x2a, 0x80, 0x02, 0xe0, 0x01,
+        ]);
+        assert_eq!(frame_size(false, &interframe), None);
+    }
+
+    #[test]
+    fn count_dropped_packets() {
+        let (sender, _receiver) = mpsc::channel(1);
+        let sink = TrackSink::new(Arc::new(Mutex::new(Some(sender))), "00-1.ogg".to_string());
+
+        assert!(sink.write(&Packet::default()));
+        assert!(sink.write(&Packet::default()));
+        assert_eq!(sink.dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn sanitize_file_name() {
+        assert_eq!(sanitize("42"), "42");
+        assert_eq!(sanitize("../a b"), "___a_b");
+    }
+
+    #[tokio::test]
+    async fn save_metadata_to_disk() {
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let recording = Arc::new(Recording::new(
+            Arc::new(LocalStorage::new(root.clone())),
+            "room",
+            "1",
+        ));
+
+        assert!(recording.add_track("2", "video", "video/H264").is_none());
+
+        let metadata = recording.finish().await.unwrap();
+        assert!(metadata.tracks.is_empty());
+        assert!(metadata.ended_at.is_some());
+
+        let saved = fs::read_to_string(root.join(&recording.id).join(METADATA_FILE)).unwrap();
+        assert!(saved.contains("\"room_id\": \"room\""));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn finish_ends_open_tracks() {
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let recording = Arc::new(Recording::new(
+            Arc::new(LocalStorage::new(root.clone())),
+            "room",
+            "1",
+        ));
+
+        // Издатель еще в комнате, его очередь открыта
+        let sink = recording.add_track("2", "audio", MIME_TYPE_OPUS).unwrap();
+
+        let metadata = recording.finish().await.unwrap();
+        assert_eq!(metadata.tracks.len(), 1);
+        assert!(metadata.tracks[0].ended_at.is_some());
+        assert!(!sink.write(&Packet::default()));
+
+        let saved = fs::read_to_string(root.join(&recording.id).join(METADATA_FILE)).unwrap();
+        assert!(!saved.contains("\"ended_at\": null"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
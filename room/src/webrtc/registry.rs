@@ -0,0 +1,88 @@
+use crate::webrtc::forward::TrackForwarder;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Weak};
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::track::track_remote::TrackRemote;
+
+// Трек однозначно определяется типом, медиапотоком (MediaStream) и своим id внутри потока.
+// Камера с микрофоном обычно приходят одним потоком, демонстрация экрана - отдельным.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackKey {
+    pub kind: RTPCodecType,
+    pub stream_id: String,
+    pub track_id: String,
+}
+
+impl TrackKey {
+    pub fn of(track: &TrackRemote) -> Self {
+        TrackKey {
+            kind: track.kind(),
+            stream_id: track.stream_id(),
+            track_id: track.id(),
+        }
+    }
+}
+
+// RTPCodecType не реализует Hash
+impl Hash for TrackKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.kind as u8).hash(state);
+        self.stream_id.hash(state);
+        self.track_id.hash(state);
+    }
+}
+
+// Реестр опубликованных треков: участник -> его треки
+#[derive(Default)]
+pub struct TrackRegistry {
+    participants: HashMap<String, HashMap<TrackKey, Weak<TrackForwarder>>>,
+}
+
+impl TrackRegistry {
+    pub fn insert(&mut self, session_id: String, forwarder: &Arc<TrackForwarder>) -> TrackKey {
+        let key = TrackKey::of(forwarder.track());
+
+        self.participants
+            .entry(session_id)
+            .or_default()
+            .insert(key.clone(), Arc::downgrade(forwarder));
+
+        key
+    }
+
+    pub fn get(&self, session_id: &str, key: &TrackKey) -> Option<Arc<TrackForwarder>> {
+        self.participants.get(session_id)?.get(key)?.upgrade()
+    }
+
+    pub fn remove_participant(&mut self, session_id: &str) {
+        self.participants.remove(session_id);
+    }
+
+    // Живые треки участника, завершившиеся треки удаляются из реестра
+    pub fn tracks_of(&mut self, session_id: &str) -> Vec<Arc<TrackForwarder>> {
+        let Some(tracks) = self.participants.get_mut(session_id) else {
+            return vec![];
+        };
+
+        tracks.retain(|_, forwarder| forwarder.strong_count() > 0);
+        let forwarders = tracks
+            .values()
+            .filter_map(|forwarder| forwarder.upgrade())
+            .collect::<Vec<_>>();
+
+        if tracks.is_empty() {
+            self.participants.remove(session_id);
+        }
+
+        forwarders
+    }
+
+    pub fn all(&self) -> Vec<Arc<TrackForwarder>> {
+        self.participants
+            .values()
+            .flat_map(|tracks| tracks.values())
+            .filter_map(|forwarder| forwarder.upgrade())
+            .collect()
+    }
+}
@@ -0,0 +1,79 @@
+use crate::webrtc::chat::ChatHistory;
+use crate::webrtc::recording::Recording;
+use crate::webrtc::sfu::Participant;
+use crate::webrtc::speaker::SpeakerDetector;
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+#[derive(Error, Debug)]
+pub enum RoomError {
+    #[error("Room is full")]
+    Full,
+    #[error("Server is shutting down")]
+    Draining,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RoomOptions {
+    // None - без ограничения на число участников
+    pub capacity: Option<usize>,
+}
+
+pub struct Room {
+    pub(crate) id: String,
+    pub(crate) capacity: Option<usize>,
+    // Создана заранее через create_room, закрывает ее создатель
+    pub(crate) reserved: bool,
+    pub(crate) created_at: Instant,
+    pub(crate) participants: Mutex<HashMap<String, Arc<Participant>>>,
+    pub(crate) speakers: Mutex<SpeakerDetector>,
+    pub(crate) chat: Mutex<ChatHistory>,
+    // Идущая запись комнаты
+    pub(crate) recording: Mutex<Option<Arc<Recording>>>,
+}
+
+impl Room {
+    pub fn new(id: String, options: RoomOptions, reserved: bool) -> Self {
+        Room {
+            id,
+            capacity: options.capacity,
+            reserved,
+            created_at: Instant::now(),
+            participants: Default::default(),
+            speakers: Default::default(),
+            chat: Default::default(),
+            recording: Default::default(),
+        }
+    }
+
+    pub fn is_full(&self, participants: usize) -> bool {
+        self.capacity
+            .is_some_and(|capacity| participants >= capacity)
+    }
+}
+
+// События жизненного цикла комнат, на них можно подписаться через Sfu::subscribe_lifecycle
+#[derive(Debug, Clone)]
+pub enum RoomLifecycle {
+    Created {
+        room_id: String,
+        capacity: Option<usize>,
+    },
+    Joined {
+        room_id: String,
+        session_id: String,
+        participants: usize,
+    },
+    Left {
+        room_id: String,
+        session_id: String,
+        participants: usize,
+    },
+    Closed {
+        room_id: String,
+        lifetime: Duration,
+    },
+}
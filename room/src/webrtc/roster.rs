@@ -0,0 +1,92 @@
+use crate::webrtc::forward::TrackForwarder;
+use crate::webrtc::metrics::kind_label;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+// Список участников комнаты, который видят клиенты. В него попадают только участники
+// с websocket сигналингом после первого подключения: издатели WHIP и наблюдатели WHEP не разговаривают.
+// Наблюдатели перечисляются отдельно, чтобы участники знали, что их смотрят.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MediaState {
+    // id трека у издателя
+    pub track_id: String,
+    pub kind: String,
+    pub muted: bool,
+}
+
+impl MediaState {
+    pub fn of(forwarder: &TrackForwarder) -> Self {
+        MediaState {
+            track_id: forwarder.track().id(),
+            kind: kind_label(forwarder.track().kind()).to_string(),
+            muted: forwarder.is_muted(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RosterEntry {
+    pub session_id: String,
+    pub user_id: i64,
+    // None - сервис account не настроен или недоступен
+    pub display_name: Option<String>,
+    pub joined_at: NaiveDateTime,
+    pub tracks: Vec<MediaState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ViewerEntry {
+    pub session_id: String,
+    pub user_id: i64,
+    pub joined_at: NaiveDateTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webrtc::sfu::RoomEvent;
+
+    #[test]
+    fn serialize_roster_events() {
+        let entry = RosterEntry {
+            session_id: "1".to_string(),
+            user_id: 1,
+            display_name: Some("alex".to_string()),
+            joined_at: NaiveDateTime::default(),
+            tracks: vec![MediaState {
+                track_id: "mic".to_string(),
+                kind: "audio".to_string(),
+                muted: true,
+            }],
+        };
+
+        let joined = serde_json::to_value(RoomEvent::ParticipantJoined {
+            participant: entry.clone(),
+        })
+        .unwrap();
+        assert_eq!(joined["event"], "participant_joined");
+        assert_eq!(joined["participant"]["display_name"], "alex");
+        assert_eq!(joined["participant"]["tracks"][0]["muted"], true);
+
+        let viewer = ViewerEntry {
+            session_id: "2".to_string(),
+            user_id: 2,
+            joined_at: NaiveDateTime::default(),
+        };
+        let roster = serde_json::to_value(RoomEvent::Roster {
+            participants: vec![entry],
+            viewers: vec![viewer.clone()],
+            recording: true,
+        })
+        .unwrap();
+        assert_eq!(roster["event"], "roster");
+        assert_eq!(roster["participants"][0]["session_id"], "1");
+        assert_eq!(roster["viewers"][0]["user_id"], 2);
+        assert_eq!(roster["recording"], true);
+
+        let joined = serde_json::to_value(RoomEvent::ViewerJoined { viewer }).unwrap();
+        assert_eq!(joined["event"], "viewer_joined");
+        assert_eq!(joined["viewer"]["session_id"], "2");
+    }
+}
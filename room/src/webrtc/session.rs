@@ -0,0 +1,95 @@
+use clap::ValueEnum;
+use thiserror::Error;
+
+// Сессия - одно подключение пользователя: вкладка, устройство или поток WHIP/WHEP.
+// id выдает сервер в виде <user_id>-<kind>-<uuid>, поэтому владельца можно проверить без хранилища.
+
+pub const WS: &str = "ws";
+pub const WHIP: &str = "whip";
+pub const WHEP: &str = "whep";
+const KINDS: [&str; 3] = [WS, WHIP, WHEP];
+
+pub fn new_session_id(user_id: i64, kind: &str) -> String {
+    format!("{user_id}-{kind}-{}", uuid::Uuid::new_v4().simple())
+}
+
+pub fn owner(session_id: &str) -> Option<i64> {
+    parse(session_id).map(|(user_id, _)| user_id)
+}
+
+fn parse(session_id: &str) -> Option<(i64, &str)> {
+    let (user_id, rest) = session_id.split_once('-')?;
+    let (kind, _) = rest.split_once('-')?;
+    if !KINDS.contains(&kind) {
+        return None;
+    }
+    Some((user_id.parse().ok()?, kind))
+}
+
+// Websocket сессию пользователя можно продолжить новым сокетом после обрыва
+pub fn resumable(session_id: &str, user_id: i64) -> bool {
+    parse(session_id) == Some((user_id, WS))
+}
+
+// Что делать с прежними websocket сессиями пользователя, когда он открывает новую.
+// Потоки WHIP/WHEP политика не затрагивает.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SessionPolicy {
+    // Новая сессия закрывает прежние, например при переходе с телефона на ноутбук
+    Replace,
+    // Сессии с разных вкладок и устройств работают одновременно
+    Multiple,
+}
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("session_id is required")]
+    Missing,
+    #[error("Session belongs to another user")]
+    Foreign,
+    #[error("Session not found")]
+    Unknown,
+}
+
+// Сессия из HTTP запроса, она должна принадлежать пользователю из JWT.
+// Что такая сессия действительно выдана, проверяет вызывающий по реестру сессий.
+pub fn verify(session_id: Option<String>, user_id: i64) -> Result<String, SessionError> {
+    let session_id = session_id.ok_or(SessionError::Missing)?;
+    if owner(&session_id) != Some(user_id) {
+        return Err(SessionError::Foreign);
+    }
+    Ok(session_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_owner() {
+        assert_eq!(owner(&new_session_id(42, WS)), Some(42));
+        assert_eq!(owner(&new_session_id(42, WHIP)), Some(42));
+        assert_eq!(owner(&new_session_id(42, WHEP)), Some(42));
+        assert_eq!(owner("42"), None);
+        assert_eq!(owner("42-http-1"), None);
+    }
+
+    #[test]
+    fn verify_session() {
+        let session_id = new_session_id(42, WS);
+        assert_eq!(verify(Some(session_id.clone()), 42).unwrap(), session_id);
+        assert!(matches!(
+            verify(Some(session_id), 7),
+            Err(SessionError::Foreign)
+        ));
+        assert!(matches!(verify(None, 42), Err(SessionError::Missing)));
+    }
+
+    #[test]
+    fn resumable_session() {
+        assert!(resumable(&new_session_id(42, WS), 42));
+        assert!(!resumable(&new_session_id(42, WS), 7));
+        assert!(!resumable(&new_session_id(42, WHIP), 42));
+        assert!(!resumable("42-ws", 42));
+    }
+}
@@ -1,51 +1,193 @@
 use std::collections::HashMap;
 use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Weak};
 
+use crate::matchmaking::MatchmakingEvent;
+use crate::webrtc::admin::{ParticipantInfo, RoomInfo, TrackInfo};
+use crate::webrtc::bwe::{BandwidthEstimator, BandwidthStats};
+use crate::webrtc::chat::{
+    ChatError, ChatEvent, ChatPayload, ChatServerMessage, RateLimiter, CHAT_LABEL,
+};
+use crate::webrtc::config::SfuConfig;
+use crate::webrtc::forward::{KeyframeRequest, TrackForwarder};
+use crate::webrtc::metrics::{kind_label, metrics};
+use crate::webrtc::recording::{Recording, RecordingError, RecordingMetadata, RecordingStorage};
+use crate::webrtc::registry::{TrackKey, TrackRegistry};
+use crate::webrtc::room::{Room, RoomError, RoomLifecycle, RoomOptions};
+use crate::webrtc::roster::{MediaState, RosterEntry, ViewerEntry};
+use crate::webrtc::session;
+use crate::webrtc::simulcast::LayerQuality;
+use crate::webrtc::speaker::TalkSummary;
+use crate::webrtc::viewer::ViewerSlots;
 use anyhow::{bail, Result};
+use chrono::{NaiveDateTime, Utc};
 use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 use std::ops::Deref;
 use std::pin::Pin;
-use tokio::sync::Mutex;
-use tokio::time::Duration;
-use webrtc::api::interceptor_registry::register_default_interceptors;
-use webrtc::api::media_engine::MediaEngine;
-use webrtc::api::APIBuilder;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, Mutex};
+use tokio::time::{Duration, Instant};
+use webrtc::api::API;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
 use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
 use webrtc::ice_transport::ice_server::RTCIceServer;
-use webrtc::interceptor::registry::Registry;
 use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::offer_answer_options::RTCOfferOptions;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::signaling_state::RTCSignalingState;
 use webrtc::peer_connection::RTCPeerConnection;
-use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
 use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
-use webrtc::track::track_local::TrackLocalWriter;
 use webrtc::track::track_remote::TrackRemote;
 use webrtc::Error;
 use webrtc::Error::ErrNoRemoteDescription;
 
+const BANDWIDTH_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+const SPEAKER_INTERVAL: Duration = Duration::from_millis(300);
+// Сколько хранить статистику последнего разговора для /talk-summary, дальше она есть в истории account
+const TALK_SUMMARY_TTL: Duration = Duration::from_secs(60 * 60);
+// Запас в оценке канала на каждый аудио трек, аудио никогда не приостанавливается
+const AUDIO_BITRATE: u64 = 64_000;
+
+// Как участник подключен к комнате
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // Клиент с websocket сигналингом: публикует и получает треки
+    Member,
+    // WHIP (OBS, GStreamer): только публикует, перепереговоры невозможны
+    Ingest,
+    // WHEP наблюдатель: только получает треки через заранее выделенные слоты.
+    // Не занимает место в комнате и не участвует в разговоре.
+    Viewer,
+}
+
 pub struct Participant {
     pub(crate) session_id: String,
+    // Владелец сессии из JWT, у пользователя может быть несколько сессий
+    pub(crate) user_id: i64,
+    pub(crate) role: Role,
+    pub(crate) joined_at: NaiveDateTime,
     pub(crate) pc: RTCPeerConnection,
     pub(crate) negotiating: Mutex<bool>,
     pub(crate) pending_negotiation: Mutex<bool>,
+    // RTCRtpSender'ы пересылаемых этому участнику треков, сгруппированные по session_id издателя
+    pub(crate) senders: Mutex<HashMap<String, Vec<Arc<RTCRtpSender>>>>,
+    // Слоты наблюдателя WHEP под треки издателей, у остальных ролей пусто
+    slots: Mutex<ViewerSlots>,
+    // Оценка канала SFU -> участник по его RTCP
+    pub(crate) bandwidth: Mutex<BandwidthEstimator>,
+    // Data channel чата, его открывает клиент
+    pub(crate) chat: Mutex<Option<Arc<RTCDataChannel>>>,
+    pub(crate) chat_limiter: Mutex<RateLimiter>,
+    // Следующий offer SFU перезапускает ICE
+    ice_restart: AtomicBool,
+    // Повторный Connected после первого - восстановление после обрыва
+    connected: AtomicBool,
+    // Когда пропало соединение, None - участник подключен
+    disconnected_at: Mutex<Option<Instant>>,
+    // Имя из сервиса account, загружается при подключении
+    display_name: Mutex<Option<String>>,
 }
 
-#[allow(clippy::type_complexity)]
 pub struct SFUInner {
-    rooms: Mutex<HashMap<String, Arc<Mutex<HashMap<String, Arc<Participant>>>>>>,
+    rooms: Mutex<HashMap<String, Arc<Room>>>,
     pub(crate) participants: Mutex<HashMap<String, Arc<Participant>>>,
-    pub(crate) remote_tracks: Mutex<HashMap<String, Weak<TrackRemote>>>,
+    pub(crate) remote_tracks: Mutex<TrackRegistry>,
     candidates_buffers: Mutex<HashMap<String, Vec<RTCIceCandidateInit>>>,
     //session: Arc<Mutex<HashMap<String, Arc<SocketClient>>>>,
-    signalling: Box<dyn Signalling>,
+    signalling: Arc<dyn Signalling>,
+    pub(crate) config: SfuConfig,
+    lifecycle: broadcast::Sender<RoomLifecycle>,
+    // API с политикой кодеков развертывания, собирается один раз
+    api: Arc<API>,
+    history: Option<Arc<dyn PracticeHistory>>,
+    // Статистика последнего разговора каждого пользователя и когда разговор закончился
+    talk_summaries: Mutex<HashMap<i64, (Instant, TalkSummary)>>,
+    // None - запись комнат выключена
+    recordings: Option<Arc<dyn RecordingStorage>>,
+    // Узел останавливается: новые участники не принимаются
+    draining: AtomicBool,
+    // Имена участников для списка комнаты, None - без имен
+    directory: Option<Arc<dyn UserDirectory>>,
 }
 
 // Selective Forwarding unit
 pub struct Sfu(Arc<SFUInner>);
 
+// События комнаты, которые рассылаются ее участникам
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RoomEvent {
+    // Полный список участников, приходит при подключении к комнате.
+    // recording - комната записывается, об этом предупреждается каждый входящий.
+    Roster {
+        participants: Vec<RosterEntry>,
+        viewers: Vec<ViewerEntry>,
+        recording: bool,
+    },
+    ParticipantJoined {
+        participant: RosterEntry,
+    },
+    // Изменились треки участника или их состояние
+    ParticipantUpdated {
+        participant: RosterEntry,
+    },
+    ParticipantLeft {
+        session_id: String,
+    },
+    // Комнату начал или перестал смотреть наблюдатель WHEP
+    ViewerJoined {
+        viewer: ViewerEntry,
+    },
+    ViewerLeft {
+        session_id: String,
+    },
+    SystemMessage {
+        message: String,
+    },
+    DominantSpeakerChanged {
+        session_id: String,
+    },
+    // Сервер останавливается, через grace_secs соединения будут закрыты
+    Draining {
+        grace_secs: u64,
+    },
+    // Участник выключил или включил трек, track_id - id трека у издателя
+    TrackMuted {
+        session_id: String,
+        track_id: String,
+        kind: String,
+        muted: bool,
+    },
+    // Запись комнаты началась, started_by - id пользователя
+    RecordingStarted {
+        recording_id: String,
+        started_by: i64,
+    },
+    RecordingStopped {
+        recording_id: String,
+    },
+}
+
+// Хранилище истории разговоров (сервис account)
+pub trait PracticeHistory: Sync + Send {
+    fn save(&self, summary: TalkSummary) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+}
+
+// Имена пользователей (сервис account)
+pub trait UserDirectory: Sync + Send {
+    fn display_name(
+        &self,
+        user_id: String,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send + '_>>;
+}
+
 pub trait Signalling: Sync + Send {
     fn send_sdp(
         &self,
@@ -57,17 +199,44 @@ pub trait Signalling: Sync + Send {
         session_id: String,
         candidate: Option<RTCIceCandidate>,
     ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+    fn send_matchmaking(
+        &self,
+        session_id: String,
+        event: MatchmakingEvent,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+    fn send_room_event(
+        &self,
+        session_id: String,
+        event: RoomEvent,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
 }
 
 impl Sfu {
-    pub fn new(signalling: Box<dyn Signalling>) -> Self {
-        Sfu(Arc::new(SFUInner {
+    pub fn new(
+        signalling: Arc<dyn Signalling>,
+        config: SfuConfig,
+        history: Option<Arc<dyn PracticeHistory>>,
+        recordings: Option<Arc<dyn RecordingStorage>>,
+        directory: Option<Arc<dyn UserDirectory>>,
+    ) -> Result<Self> {
+        let (lifecycle, _) = broadcast::channel(64);
+        let api = Arc::new(config.codecs.build_api()?);
+
+        Ok(Sfu(Arc::new(SFUInner {
             signalling,
             participants: Default::default(),
             rooms: Default::default(),
             remote_tracks: Default::default(),
             candidates_buffers: Default::default(),
-        }))
+            config,
+            lifecycle,
+            api,
+            history,
+            talk_summaries: Default::default(),
+            recordings,
+            draining: AtomicBool::new(false),
+            directory,
+        })))
     }
 }
 
@@ -85,96 +254,346 @@ impl Deref for Sfu {
 }
 
 impl Sfu {
+    pub fn subscribe_lifecycle(&self) -> broadcast::Receiver<RoomLifecycle> {
+        self.lifecycle.subscribe()
+    }
+
+    fn emit(&self, event: RoomLifecycle) {
+        match &event {
+            RoomLifecycle::Created { room_id, capacity } => {
+                info!(room:? = room_id, capacity:? = capacity; "Room created")
+            }
+            RoomLifecycle::Joined {
+                room_id,
+                session_id,
+                participants,
+            } => {
+                info!(room:? = room_id, user:? = session_id, participants:? = participants; "Participant joined room")
+            }
+            RoomLifecycle::Left {
+                room_id,
+                session_id,
+                participants,
+            } => {
+                info!(room:? = room_id, user:? = session_id, participants:? = participants; "Participant left room")
+            }
+            RoomLifecycle::Closed { room_id, lifetime } => {
+                info!(room:? = room_id, lifetime:? = lifetime; "Room closed")
+            }
+        }
+        // Ошибка означает лишь отсутствие подписчиков
+        _ = self.lifecycle.send(event);
+    }
+
+    fn room_entry(
+        &self,
+        rooms: &mut HashMap<String, Arc<Room>>,
+        room_id: String,
+        options: RoomOptions,
+        reserved: bool,
+    ) -> Arc<Room> {
+        if let Some(room) = rooms.get(&room_id) {
+            return Arc::clone(room);
+        }
+
+        let capacity = options.capacity;
+        let room = Arc::new(Room::new(room_id.clone(), options, reserved));
+        rooms.insert(room_id.clone(), Arc::clone(&room));
+        self.emit(RoomLifecycle::Created { room_id, capacity });
+
+        room
+    }
+
+    // Явное создание комнаты, например 1:1 комнаты для рулетки. Сборщик пустых комнат ее не трогает,
+    // создатель закрывает ее через release_room, когда комната больше не нужна.
+    pub async fn create_room(&self, room_id: String, options: RoomOptions) -> Arc<Room> {
+        let mut rooms = self.rooms.lock().await;
+        self.room_entry(&mut rooms, room_id, options, true)
+    }
+
+    // Закрывает созданную через create_room комнату, если в ней никого нет.
+    // Иначе комната закроется, когда уйдет последний участник.
+    pub async fn release_room(&self, room_id: &str) {
+        let room = self.rooms.lock().await.get(room_id).cloned();
+        if let Some(room) = room {
+            self.close_room_if_empty(&room).await;
+        }
+    }
+
+    async fn close_room_if_empty(&self, room: &Arc<Room>) {
+        let mut rooms = self.rooms.lock().await;
+        if !room.participants.lock().await.is_empty() {
+            return;
+        }
+
+        if rooms.get(&room.id).is_some_and(|r| Arc::ptr_eq(r, room)) {
+            rooms.remove(&room.id);
+            self.emit(RoomLifecycle::Closed {
+                room_id: room.id.clone(),
+                lifetime: room.created_at.elapsed(),
+            });
+        }
+        drop(rooms);
+
+        let recording = room.recording.lock().await.take();
+        if let Some(recording) = recording {
+            if let Err(e) = recording.finish().await {
+                warn!(room:? = room.id, err:? = e; "Could not finish recording");
+            }
+        }
+    }
+
+    // Удаляет комнаты, в которые так никто и не зашел
+    pub fn spawn_room_gc(&self) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(this.config.empty_room_ttl);
+            loop {
+                interval.tick().await;
+
+                let rooms = this
+                    .rooms
+                    .lock()
+                    .await
+                    .values()
+                    .filter(|room| {
+                        !room.reserved && room.created_at.elapsed() >= this.config.empty_room_ttl
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                for room in rooms {
+                    this.close_room_if_empty(&room).await;
+                }
+            }
+        });
+    }
+
+    // Раз в BANDWIDTH_UPDATE_INTERVAL делит оценку канала каждого подписчика между его видео треками
+    // и ограничивает издателей через REMB
+    pub fn spawn_bandwidth_controller(&self) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(BANDWIDTH_UPDATE_INTERVAL);
+            let mut logged = HashMap::new();
+            loop {
+                interval.tick().await;
+                this.update_bandwidth(&mut logged).await;
+            }
+        });
+    }
+
+    async fn update_bandwidth(&self, logged: &mut HashMap<String, u64>) {
+        let forwarders = self.remote_tracks.lock().await.all();
+
+        let mut video_tracks = HashMap::<String, u64>::new();
+        let mut audio_tracks = HashMap::<String, u64>::new();
+        let mut subscriptions = vec![];
+        for forwarder in forwarders {
+            // Выключенные и приостановленные треки не занимают канал подписчика
+            let subscribers = forwarder.active_subscriber_ids().await;
+            let tracks = match forwarder.track().kind() {
+                RTPCodecType::Video => &mut video_tracks,
+                _ => &mut audio_tracks,
+            };
+            for session_id in &subscribers {
+                *tracks.entry(session_id.clone()).or_default() += 1;
+            }
+            if forwarder.track().kind() == RTPCodecType::Video {
+                subscriptions.push((forwarder, subscribers));
+            }
+        }
+
+        let participants = self.participants.lock().await.clone();
+        logged.retain(|session_id, _| participants.contains_key(session_id));
+
+        let mut budgets = HashMap::new();
+        for (session_id, participant) in participants {
+            let Some(video) = video_tracks.get(&session_id) else {
+                continue;
+            };
+            let audio = audio_tracks.get(&session_id).copied().unwrap_or(0);
+
+            let stats = participant.bandwidth.lock().await.stats();
+            let budget = stats.estimate.saturating_sub(AUDIO_BITRATE * audio) / video;
+
+            // В лог попадают только заметные изменения оценки
+            let last = logged.get(&session_id).copied().unwrap_or(0);
+            if stats.estimate.abs_diff(last) * 5 > last {
+                info!(user:? = session_id, estimate:? = stats.estimate, remb:? = stats.remb, loss:? = stats.loss, source:? = stats.source, budget:? = budget; "Bandwidth estimate");
+                logged.insert(session_id.clone(), stats.estimate);
+            }
+
+            budgets.insert(session_id, budget);
+        }
+
+        for (forwarder, subscribers) in subscriptions {
+            let mut cap = None;
+            for session_id in subscribers {
+                if let Some(budget) = budgets.get(&session_id) {
+                    forwarder.set_budget(&session_id, *budget).await;
+                    cap = cap.max(Some(*budget));
+                }
+            }
+
+            if let Some(cap) = cap {
+                forwarder.send_remb(cap).await;
+            }
+        }
+    }
+
+    // Обновляет gauges перед сбором метрик
+    pub async fn update_metrics(&self) {
+        metrics().rooms.set(self.rooms.lock().await.len() as i64);
+        metrics()
+            .participants
+            .set(self.participants.lock().await.len() as i64);
+
+        let forwarders = self.remote_tracks.lock().await.all();
+        metrics().tracks.reset();
+        for forwarder in forwarders {
+            metrics()
+                .tracks
+                .with_label_values(&[kind_label(forwarder.track().kind())])
+                .inc();
+        }
+    }
+
+    // Оценка канала до участника
+    pub async fn bandwidth_stats(&self, session_id: &str) -> Option<BandwidthStats> {
+        let participant = self.participants.lock().await.get(session_id).cloned()?;
+        let stats = participant.bandwidth.lock().await.stats();
+        Some(stats)
+    }
+
     async fn get_or_create_peer(
         &self,
         session_id: String,
         room_id: String,
+        role: Role,
     ) -> Result<Arc<Participant>> {
-        let room = self
-            .rooms
-            .lock()
-            .await
-            .entry(room_id.clone())
-            .or_default()
-            .clone();
-        let mut room_map = room.lock().await;
+        let Some(user_id) = session::owner(&session_id) else {
+            bail!("invalid session id")
+        };
+
+        // Блокировка комнат держится до блокировки участников, чтобы комнату не удалили между ними
+        let mut rooms = self.rooms.lock().await;
+        let options = RoomOptions {
+            capacity: self.config.room_capacity,
+        };
+        let room = self.room_entry(&mut rooms, room_id.clone(), options, false);
+        let mut room_map = room.participants.lock().await;
+        drop(rooms);
 
         let peer: Arc<Participant>;
-        if room_map.contains_key(&session_id) {
-            return match room_map.get(&session_id) {
-                Some(peer) => Ok(peer.clone()),
-                None => anyhow::bail!("Could not find peer {}", session_id),
-            };
+        if let Some(peer) = room_map.get(&session_id) {
+            return Ok(peer.clone());
+        }
+        if self.is_draining() {
+            return Err(RoomError::Draining.into());
+        }
+
+        let members = room_map
+            .values()
+            .filter(|participant| participant.role != Role::Viewer)
+            .count();
+        if role != Role::Viewer && room.is_full(members) {
+            return Err(RoomError::Full.into());
         }
 
-        let pc = create_peer(session_id.clone()).await?;
+        let ice_servers = self.config.ice.ice_servers(&session_id);
+        let pc = create_peer(&self.api, session_id.clone(), ice_servers).await?;
         peer = Arc::new(Participant {
             session_id: session_id.clone(),
+            user_id,
+            role,
+            joined_at: Utc::now().naive_utc(),
             pc,
             negotiating: Mutex::new(false),
             pending_negotiation: Mutex::new(false),
+            senders: Mutex::new(HashMap::new()),
+            slots: Default::default(),
+            bandwidth: Default::default(),
+            chat: Default::default(),
+            chat_limiter: Default::default(),
+            ice_restart: AtomicBool::new(false),
+            connected: AtomicBool::new(false),
+            disconnected_at: Mutex::new(None),
+            display_name: Mutex::new(None),
         });
 
         room_map.insert(peer.session_id.clone(), Arc::clone(&peer));
+        if role != Role::Viewer {
+            room.speakers.lock().await.join(&session_id);
+        }
+        self.emit(RoomLifecycle::Joined {
+            room_id: room_id.clone(),
+            session_id: session_id.clone(),
+            participants: room_map.len(),
+        });
+        drop(room_map);
+
         let mut participants = self.participants.lock().await;
         if let Some(participant) = participants.get(&session_id) {
             _ = participant.pc.close().await;
         }
         participants.insert(session_id.clone(), Arc::clone(&peer));
 
-        let this = self.clone();
-        let session_id = session_id.clone();
-        peer.pc.on_ice_candidate(Box::new(move |c: Option<RTCIceCandidate>| {
-            let this = this.clone();
+        // Без сигналинга кандидаты уходят в answer после сбора
+        if role == Role::Member {
+            let this = self.clone();
             let session_id = session_id.clone();
-            Box::pin(async move {
-                if let Err(e) = this.signalling.send_ice_candidate(session_id.clone(), c).await {
-                    warn!(err:? = e, user:? = session_id.clone(); "Could not send ice candidate");
-                }
-            })
-        }));
+            peer.pc.on_ice_candidate(Box::new(move |c: Option<RTCIceCandidate>| {
+                let this = this.clone();
+                let session_id = session_id.clone();
+                Box::pin(async move {
+                    if let Err(e) = this.signalling.send_ice_candidate(session_id.clone(), c).await {
+                        warn!(err:? = e, user:? = session_id.clone(); "Could not send ice candidate");
+                    }
+                })
+            }));
+        }
 
         let this = self.clone();
         let room2 = Arc::clone(&room);
         let room_id2 = room_id.clone();
         let session_id = peer.session_id.clone();
         let w_peer = Arc::downgrade(&peer);
-        Arc::clone(&peer).pc.on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
-            let room = Arc::clone(&room2);
-            let this = this.clone();
-            let room_id2 = room_id2.clone();
-            let session_id = session_id.clone();
-            let w_peer = w_peer.clone();
-            Box::pin(async move {
-                let Some(peer) = w_peer.upgrade() else {
-                    return;
-                };
-
+        Arc::clone(&peer)
+            .pc
+            .on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
+                let room = Arc::clone(&room2);
+                let this = this.clone();
                 let room_id2 = room_id2.clone();
-                match s {
-                    RTCPeerConnectionState::Closed | RTCPeerConnectionState::Failed => {
-                        if room.lock().await.remove(session_id.as_str()).is_none() { warn!(user:? = session_id.clone(), room:? = room_id2.clone(); "Not found session_id in room") }
-                        if this.participants.lock().await.remove(session_id.as_str()).is_none() { warn!(user:? = session_id.clone(), room:? = room_id2.clone(); "Not found session_id in room") }
-
-                        this.candidates_buffers.lock().await.remove(&session_id);
-                        this.remote_tracks.lock().await.remove(&session_id);
-                    }
-                    RTCPeerConnectionState::Connected => {
-                        this.on_connected(peer, room).await;
-                    }
-                    _ => {}
-                }
-
-                let peers = this.participants.lock().await.values()
-                    .map(|p| p.session_id.clone()).collect::<Vec<String>>();
-                info!(
+                let session_id = session_id.clone();
+                let w_peer = w_peer.clone();
+                Box::pin(async move {
+                    metrics()
+                        .peer_states
+                        .with_label_values(&[&s.to_string()])
+                        .inc();
+                    let Some(peer) = w_peer.upgrade() else {
+                        return;
+                    };
+
+                    let room_id2 = room_id2.clone();
+                    this.on_peer_state(peer, room, s).await;
+
+                    let peers = this
+                        .participants
+                        .lock()
+                        .await
+                        .values()
+                        .map(|p| p.session_id.clone())
+                        .collect::<Vec<String>>();
+                    info!(
                         user:? = session_id,
                         room:? = room_id2.clone(),
                         peers:? = peers,
                         state:? = s;
                         "Peer state changed");
-            })
-        }));
+                })
+            }));
 
         let session_id = peer.session_id.clone();
         let this = self.clone();
@@ -193,9 +612,256 @@ impl Sfu {
             })
         }));
 
+        let this = self.clone();
+        let w_peer = Arc::downgrade(&peer);
+        let w_room = Arc::downgrade(&room);
+        peer.pc.on_data_channel(Box::new(move |channel| {
+            let this = this.clone();
+            let w_peer = w_peer.clone();
+            let w_room = w_room.clone();
+            Box::pin(async move {
+                this.on_data_channel(w_peer, w_room, channel).await;
+            })
+        }));
+
         Ok(Arc::clone(&peer))
     }
 
+    async fn remove_peer(&self, peer: &Participant, room: &Arc<Room>) {
+        let session_id = peer.session_id.as_str();
+        let left = {
+            let mut room_map = room.participants.lock().await;
+            room_map.remove(session_id).map(|_| room_map.len())
+        };
+        match left {
+            Some(participants) => self.emit(RoomLifecycle::Left {
+                room_id: room.id.clone(),
+                session_id: session_id.to_string(),
+                participants,
+            }),
+            None => warn!(user:? = session_id, room:? = room.id; "Not found session_id in room"),
+        }
+        if self.participants.lock().await.remove(session_id).is_none() {
+            warn!(user:? = session_id, room:? = room.id; "Not found session_id in room")
+        }
+
+        self.candidates_buffers.lock().await.remove(session_id);
+        self.remote_tracks
+            .lock()
+            .await
+            .remove_participant(session_id);
+        self.unsubscribe_all(session_id).await;
+        self.on_participant_left(peer, Arc::clone(room)).await;
+        self.close_room_if_empty(room).await;
+    }
+
+    async fn on_peer_state(
+        &self,
+        peer: Arc<Participant>,
+        room: Arc<Room>,
+        state: RTCPeerConnectionState,
+    ) {
+        match state {
+            RTCPeerConnectionState::Closed => {
+                self.remove_peer(&peer, &room).await;
+            }
+            // Клиент с сигналингом может восстановить соединение перезапуском ICE
+            RTCPeerConnectionState::Disconnected | RTCPeerConnectionState::Failed
+                if peer.role == Role::Member =>
+            {
+                self.on_connection_lost(peer).await;
+            }
+            RTCPeerConnectionState::Failed => {
+                self.remove_peer(&peer, &room).await;
+            }
+            RTCPeerConnectionState::Connected => {
+                *peer.disconnected_at.lock().await = None;
+                if peer.connected.swap(true, Ordering::Relaxed) {
+                    self.on_resumed(peer, room).await;
+                } else {
+                    self.on_connected(peer, room).await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Соединение пропало, например при смене сети. SFU перезапускает ICE, а участник
+    // с подписками остается в комнате reconnect_window, после чего удаляется.
+    async fn on_connection_lost(&self, peer: Arc<Participant>) {
+        let lost_at = {
+            let mut disconnected_at = peer.disconnected_at.lock().await;
+            match *disconnected_at {
+                // Failed после Disconnected: окно переподключения уже идет
+                Some(_) => None,
+                None => {
+                    let now = Instant::now();
+                    *disconnected_at = Some(now);
+                    Some(now)
+                }
+            }
+        };
+
+        let this = self.clone();
+        let restarted = Arc::clone(&peer);
+        tokio::spawn(async move {
+            this.restart_ice(&restarted).await;
+        });
+
+        let Some(lost_at) = lost_at else {
+            return;
+        };
+        info!(user:? = peer.session_id, window:? = self.config.reconnect_window; "Peer connection lost, waiting for reconnection");
+
+        let reconnect_window = self.config.reconnect_window;
+        let w_peer = Arc::downgrade(&peer);
+        tokio::spawn(async move {
+            tokio::time::sleep(reconnect_window).await;
+            let Some(peer) = w_peer.upgrade() else {
+                return;
+            };
+            if *peer.disconnected_at.lock().await != Some(lost_at) {
+                return;
+            }
+
+            info!(user:? = peer.session_id; "Peer did not reconnect in time");
+            if let Err(e) = peer.pc.close().await {
+                warn!(user:? = peer.session_id, err:? = e; "Could not close peer connection");
+            }
+        });
+    }
+
+    // Offer с новыми ICE учетными данными. Если websocket клиента оборван,
+    // offer не дойдет и будет отправлен заново при возобновлении сессии или повторном join.
+    async fn restart_ice(&self, peer: &Arc<Participant>) {
+        info!(user:? = peer.session_id; "Restarting ICE");
+        peer.ice_restart.store(true, Ordering::Relaxed);
+        if let Err(e) = self.on_negotiation_needed(Arc::clone(peer)).await {
+            warn!(user:? = peer.session_id, err:? = e; "Could not restart ICE");
+        }
+    }
+
+    // Участник вернулся с теми же подписками. Ему нужны треки, опубликованные за время обрыва,
+    // и ключевые кадры, чтобы видео не стояло до следующего.
+    async fn on_resumed(&self, peer: Arc<Participant>, room: Arc<Room>) {
+        if peer.role != Role::Member {
+            return;
+        }
+        info!(user:? = peer.session_id, room:? = room.id; "Peer resumed");
+
+        // Пока соединения не было, события комнаты могли не дойти
+        self.send_roster(&peer.session_id, &room).await;
+
+        for forwarder in self.room_tracks(&room).await {
+            if forwarder.publisher() == peer.session_id {
+                continue;
+            }
+
+            if forwarder.subscriber_ids().await.contains(&peer.session_id) {
+                forwarder
+                    .request_keyframe(&peer.session_id, KeyframeRequest::Pli)
+                    .await;
+            } else {
+                let this = self.clone();
+                let peer = Arc::clone(&peer);
+                tokio::spawn(async move {
+                    this.send_track_to_participant(forwarder, peer).await;
+                });
+            }
+        }
+    }
+
+    async fn on_data_channel(
+        &self,
+        peer: Weak<Participant>,
+        room: Weak<Room>,
+        channel: Arc<RTCDataChannel>,
+    ) {
+        let Some(peer) = peer.upgrade() else {
+            return;
+        };
+        if channel.label() != CHAT_LABEL {
+            warn!(user:? = peer.session_id, label:? = channel.label(); "Unknown data channel");
+            return;
+        }
+
+        info!(user:? = peer.session_id; "Chat channel opened");
+        *peer.chat.lock().await = Some(Arc::clone(&channel));
+
+        // Опоздавший участник получает последние сообщения комнаты
+        let w_room = room.clone();
+        let w_channel = Arc::downgrade(&channel);
+        channel.on_open(Box::new(move || {
+            Box::pin(async move {
+                let (Some(room), Some(channel)) = (w_room.upgrade(), w_channel.upgrade()) else {
+                    return;
+                };
+                let events = room.chat.lock().await.events();
+                if !events.is_empty() {
+                    send_chat(&channel, &ChatServerMessage::History { events }).await;
+                }
+            })
+        }));
+
+        let this = self.clone();
+        let w_peer = Arc::downgrade(&peer);
+        channel.on_message(Box::new(move |message: DataChannelMessage| {
+            let this = this.clone();
+            let w_peer = w_peer.clone();
+            let room = room.clone();
+            Box::pin(async move {
+                let (Some(peer), Some(room)) = (w_peer.upgrade(), room.upgrade()) else {
+                    return;
+                };
+                if let Err(e) = this.on_chat_message(&peer, &room, &message.data).await {
+                    warn!(user:? = peer.session_id, err:? = e; "Chat message rejected");
+                    if let Some(channel) = peer.chat.lock().await.clone() {
+                        let error = ChatServerMessage::Error {
+                            message: e.to_string(),
+                        };
+                        send_chat(&channel, &error).await;
+                    }
+                }
+            })
+        }));
+    }
+
+    // Рассылает сообщение чата остальным участникам комнаты
+    async fn on_chat_message(
+        &self,
+        peer: &Participant,
+        room: &Room,
+        data: &[u8],
+    ) -> Result<(), ChatError> {
+        if !peer.chat_limiter.lock().await.try_acquire(Instant::now()) {
+            return Err(ChatError::RateLimited);
+        }
+
+        let event = ChatEvent {
+            from: peer.session_id.clone(),
+            sent_at: Utc::now().timestamp_millis(),
+            payload: ChatPayload::parse(data)?,
+        };
+
+        let participants = room
+            .participants
+            .lock()
+            .await
+            .values()
+            .filter(|participant| participant.session_id != peer.session_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        for participant in participants {
+            if let Some(channel) = participant.chat.lock().await.clone() {
+                send_chat(&channel, &ChatServerMessage::Event(&event)).await;
+            }
+        }
+
+        room.chat.lock().await.push(event, self.config.chat_history);
+
+        Ok(())
+    }
+
     async fn on_negotiation_needed(&self, peer: Arc<Participant>) -> Result<()> {
         // Check if already negotiating
         {
@@ -210,6 +876,9 @@ impl Sfu {
 
         // Perform negotiation
         let result = self.do_negotiation(&peer).await;
+        if result.is_err() {
+            metrics().negotiation_failures.inc();
+        }
 
         // Check if there's a pending negotiation to handle
         loop {
@@ -229,6 +898,7 @@ impl Sfu {
 
             // Handle pending negotiation
             if let Err(e) = self.do_negotiation(&peer).await {
+                metrics().negotiation_failures.inc();
                 error!(user:? = peer.session_id, err:? = e; "Failed pending negotiation");
             }
         }
@@ -240,7 +910,14 @@ impl Sfu {
     }
 
     async fn do_negotiation(&self, peer: &Arc<Participant>) -> Result<()> {
-        let sdp = peer.pc.create_offer(None).await?;
+        let options = peer
+            .ice_restart
+            .swap(false, Ordering::Relaxed)
+            .then(|| RTCOfferOptions {
+                ice_restart: true,
+                ..Default::default()
+            });
+        let sdp = peer.pc.create_offer(options).await?;
         peer.pc.set_local_description(sdp.clone()).await?;
 
         self.signalling.send_sdp(peer.session_id.clone(), sdp).await
@@ -261,65 +938,65 @@ impl Sfu {
         }
         let room = room.unwrap();
 
-        let media_ssrc = new_track.ssrc();
+        let this = self.clone();
 
-        let peer2 = peer.clone();
-        tokio::spawn(async move {
-            let mut result = Result::<usize>::Ok(0);
-            while result.is_ok() {
-                let timeout = tokio::time::sleep(Duration::from_secs(3));
-                tokio::pin!(timeout);
-
-                tokio::select! {
-                    _ = timeout.as_mut() =>{
-                        if let Some(pc) = peer.upgrade(){
-                            // println!("strong count {:?}", Arc::strong_count(&pc));
-
-                            result = pc.pc.write_rtcp(&[Box::new(PictureLossIndication{
-                                sender_ssrc: 0,
-                                media_ssrc,
-                            })]).await.map_err(Into::into);
-                        } else {
-                            break; // safe exit
-                        }
+        if let Some(peer) = peer.upgrade() {
+            let session_id = peer.session_id.clone();
+            let forwarder = {
+                let mut remote_tracks = this.remote_tracks.lock().await;
+
+                // Остальные simulcast слои приходят отдельными on_track с тем же id трека
+                if !new_track.rid().is_empty() {
+                    let key = TrackKey::of(&new_track);
+                    if let Some(forwarder) = remote_tracks.get(&session_id, &key) {
+                        info!(user:? = session_id, track:? = key, rid:? = new_track.rid(); "Simulcast layer published");
+                        forwarder.add_layer(new_track);
+                        return;
                     }
                 }
-            }
-            info!("Peer left");
-        });
 
-        let this = self.clone();
+                let forwarder = TrackForwarder::spawn(&peer, room, new_track);
+                let key = remote_tracks.insert(session_id.clone(), &forwarder);
+                info!(user:? = session_id, track:? = key; "Track published");
+                forwarder
+            };
 
-        if let Some(peer) = peer2.upgrade() {
-            let session_id = peer.session_id.clone();
-            this.remote_tracks
-                .lock()
-                .await
-                .insert(session_id.clone(), Arc::downgrade(&new_track));
+            if let Some(recording) = room.recording.lock().await.as_ref() {
+                forwarder.start_recording(recording).await;
+            }
 
             let participants = room
+                .participants
                 .lock()
                 .await
                 .clone()
                 .into_iter()
+                // Издатель WHIP ничего не получает, наблюдатель WHEP получает трек в свободный слот
                 .filter(move |(_, participant)| {
                     participant.session_id.clone() != session_id.clone()
+                        && participant.role != Role::Ingest
                 })
                 .filter(move |(_, participant)| {
-                    participant
-                        .pc
-                        .connection_state()
-                        .eq(&RTCPeerConnectionState::Connected)
+                    participant.role == Role::Viewer
+                        || participant
+                            .pc
+                            .connection_state()
+                            .eq(&RTCPeerConnectionState::Connected)
                 });
 
             participants.for_each(|(_, participant)| {
-                let new_track = Arc::clone(&new_track);
+                let forwarder = Arc::clone(&forwarder);
                 let participant = Arc::clone(&participant);
                 let this = this.clone();
                 tokio::spawn(async move {
-                    this.send_track_to_participant(new_track, participant).await;
+                    this.send_track_to_participant(forwarder, participant).await;
                 });
             });
+
+            // Рассылка событий снова берет блокировку комнат
+            let room = Arc::clone(room);
+            drop(rooms);
+            this.on_participant_updated(&peer, &room).await;
         }
     }
 
@@ -329,10 +1006,92 @@ impl Sfu {
         offer: RTCSessionDescription,
         room_id: String,
     ) -> Result<RTCSessionDescription> {
-        let peer = self.get_or_create_peer(session_id.clone(), room_id).await?;
+        let peer = self
+            .get_or_create_peer(session_id.clone(), room_id, Role::Member)
+            .await?;
+        self.answer(&peer, offer).await
+    }
+
+    // Публикация через WHIP. В answer уже есть все кандидаты SFU, трикл от SFU не нужен.
+    pub async fn accept_ingest_offer(
+        &self,
+        session_id: String,
+        offer: RTCSessionDescription,
+        room_id: String,
+    ) -> Result<RTCSessionDescription> {
+        let peer = self
+            .get_or_create_peer(session_id, room_id.clone(), Role::Ingest)
+            .await?;
+        let answer = self.answer_or_leave(&peer, &room_id, offer).await?;
+
+        Ok(peer.pc.local_description().await.unwrap_or(answer))
+    }
+
+    // Просмотр через WHEP. Слоты под треки добавляются до answer и занимают секции offer,
+    // текущие треки сразу ставятся в слоты, будущие - по мере публикации.
+    pub async fn accept_viewer_offer(
+        &self,
+        session_id: String,
+        offer: RTCSessionDescription,
+        room_id: String,
+    ) -> Result<RTCSessionDescription> {
+        let peer = self
+            .get_or_create_peer(session_id, room_id.clone(), Role::Viewer)
+            .await?;
+        let Some(room) = self.rooms.lock().await.get(&room_id).cloned() else {
+            bail!("room not found")
+        };
+
+        match ViewerSlots::allocate(&peer.pc, &peer.session_id, &offer, &self.config.codecs).await {
+            Ok(slots) => *peer.slots.lock().await = slots,
+            Err(e) => {
+                if let Err(e) = self.leave(&peer.session_id, &room_id).await {
+                    warn!(user:? = peer.session_id, err:? = e; "Could not remove viewer without slots");
+                }
+                return Err(e);
+            }
+        }
+
+        for forwarder in self.room_tracks(&room).await {
+            self.send_track_to_participant(forwarder, Arc::clone(&peer))
+                .await;
+        }
+
+        let answer = self.answer_or_leave(&peer, &room_id, offer).await?;
+
+        let joined = RoomEvent::ViewerJoined {
+            viewer: viewer_entry(&peer),
+        };
+        self.send_to_members(Some(&room_id), joined).await;
+
+        Ok(peer.pc.local_description().await.unwrap_or(answer))
+    }
+
+    // Клиент WHIP/WHEP узнает id сессии только из ответа, без answer участник так и занимал бы место
+    async fn answer_or_leave(
+        &self,
+        peer: &Participant,
+        room_id: &str,
+        offer: RTCSessionDescription,
+    ) -> Result<RTCSessionDescription> {
+        let answer = self.answer(peer, offer).await;
+        if answer.is_err() {
+            if let Err(e) = self.leave(&peer.session_id, room_id).await {
+                warn!(user:? = peer.session_id, err:? = e; "Could not remove peer without answer");
+            }
+        }
+        answer
+    }
+
+    async fn answer(
+        &self,
+        peer: &Participant,
+        offer: RTCSessionDescription,
+    ) -> Result<RTCSessionDescription> {
+        let session_id = &peer.session_id;
         peer.pc.set_remote_description(offer).await?;
 
-        match self.candidates_buffers.lock().await.get_mut(&session_id) {
+        match self.candidates_buffers.lock().await.get_mut(session_id) {
             Some(candidates) => {
                 while let Some(candidate) = candidates.pop() {
                     peer.pc.add_ice_candidate(candidate).await?
@@ -352,6 +1111,250 @@ impl Sfu {
         Ok(answer)
     }
 
+    // Регистрирует участника в комнате до offer, чтобы можно было принимать кандидатов.
+    // Повторный join в окне переподключения возобновляет сессию вместо создания новой.
+    pub async fn join(&self, session_id: String, room_id: String) -> Result<()> {
+        let peer = self
+            .get_or_create_peer(session_id, room_id, Role::Member)
+            .await?;
+        if peer.disconnected_at.lock().await.is_some() {
+            self.restart_ice(&peer).await;
+        }
+        Ok(())
+    }
+
+    // Соединение сессии оборвано, но окно переподключения еще идет
+    pub async fn in_reconnect_window(&self, session_id: &str) -> bool {
+        let peer = self.participants.lock().await.get(session_id).cloned();
+        match peer {
+            Some(peer) => peer.disconnected_at.lock().await.is_some(),
+            None => false,
+        }
+    }
+
+    // Сессия вернулась с новым websocket. Offer перезапуска ICE ушел в закрытый сокет, он отправляется заново.
+    pub async fn resume(&self, session_id: &str) {
+        let peer = self.participants.lock().await.get(session_id).cloned();
+        if let Some(peer) = peer {
+            if peer.disconnected_at.lock().await.is_some() {
+                self.restart_ice(&peer).await;
+            }
+        }
+    }
+
+    // Участник комнаты, если он еще подключен
+    pub async fn find_peer(&self, session_id: &str, room_id: &str) -> Option<Arc<Participant>> {
+        let room = self.rooms.lock().await.get(room_id).cloned()?;
+        let peer = room.participants.lock().await.get(session_id).cloned();
+        peer
+    }
+
+    // Снимок всех комнат для администраторов
+    pub async fn rooms_overview(&self) -> Vec<RoomInfo> {
+        let rooms = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut overview = vec![];
+        for room in rooms {
+            let participants = room
+                .participants
+                .lock()
+                .await
+                .values()
+                .cloned()
+                .collect::<Vec<_>>();
+
+            let mut infos = vec![];
+            for participant in participants {
+                let forwarders = self
+                    .remote_tracks
+                    .lock()
+                    .await
+                    .tracks_of(&participant.session_id);
+                let mut tracks = vec![];
+                for forwarder in forwarders {
+                    let track = forwarder.track();
+                    tracks.push(TrackInfo {
+                        id: track.id(),
+                        kind: kind_label(track.kind()),
+                        mime_type: track.codec().capability.mime_type,
+                        subscribers: forwarder.subscriber_ids().await.len(),
+                    });
+                }
+
+                infos.push(ParticipantInfo {
+                    session_id: participant.session_id.clone(),
+                    user_id: participant.user_id,
+                    role: participant.role,
+                    state: participant.pc.connection_state().to_string(),
+                    joined_at: participant.joined_at,
+                    tracks,
+                });
+            }
+
+            overview.push(RoomInfo {
+                room_id: room.id.clone(),
+                capacity: room.capacity,
+                age_secs: room.created_at.elapsed().as_secs(),
+                recording: room.recording.lock().await.is_some(),
+                participants: infos,
+            });
+        }
+
+        overview
+    }
+
+    // Закрывает соединения всех участников, комната удалится вместе с последним из них
+    pub async fn close_room(&self, room_id: &str) -> bool {
+        let Some(room) = self.rooms.lock().await.get(room_id).cloned() else {
+            return false;
+        };
+
+        let participants = room
+            .participants
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        info!(room:? = room_id, participants:? = participants.len(); "Closing room");
+
+        for participant in participants {
+            if let Err(e) = participant.pc.close().await {
+                warn!(user:? = participant.session_id, err:? = e; "Could not close peer connection");
+            }
+        }
+        self.close_room_if_empty(&room).await;
+
+        true
+    }
+
+    // Участники с сигналингом в комнате или, без room_id, во всех комнатах
+    async fn members(&self, room_id: Option<&str>) -> Vec<String> {
+        let rooms = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .filter(|room| room_id.is_none_or(|room_id| room.id == room_id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut members = vec![];
+        for room in rooms {
+            members.extend(
+                room.participants
+                    .lock()
+                    .await
+                    .values()
+                    .filter(|participant| participant.role == Role::Member)
+                    .map(|participant| participant.session_id.clone()),
+            );
+        }
+        members
+    }
+
+    // Рассылает событие, возвращает число доставленных
+    async fn send_to_members(&self, room_id: Option<&str>, event: RoomEvent) -> usize {
+        let mut sent = 0;
+        for session_id in self.members(room_id).await {
+            match self
+                .signalling
+                .send_room_event(session_id.clone(), event.clone())
+                .await
+            {
+                Ok(()) => sent += 1,
+                Err(e) => {
+                    warn!(user:? = session_id, err:? = e, event:? = event; "Could not send room event")
+                }
+            }
+        }
+        sent
+    }
+
+    // Системное сообщение участникам комнаты или, без room_id, всех комнат
+    pub async fn broadcast(&self, room_id: Option<&str>, message: &str) -> usize {
+        let event = RoomEvent::SystemMessage {
+            message: message.to_string(),
+        };
+        self.send_to_members(room_id, event).await
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    // Перестает принимать участников и предупреждает подключенных об остановке
+    pub async fn start_draining(&self, grace: Duration) {
+        self.draining.store(true, Ordering::Relaxed);
+
+        let event = RoomEvent::Draining {
+            grace_secs: grace.as_secs(),
+        };
+        let notified = self.send_to_members(None, event).await;
+        info!(participants:? = notified, grace:? = grace; "Draining node");
+    }
+
+    // Ждет, пока участники сами покинут комнаты. false - не успели за timeout.
+    pub async fn wait_rooms_empty(&self, timeout: Duration) -> bool {
+        let mut lifecycle = self.subscribe_lifecycle();
+        tokio::time::timeout(timeout, async {
+            while !self.rooms_empty().await {
+                // Комнату меняет любое событие жизненного цикла, после него проверяем снова
+                if let Err(RecvError::Closed) = lifecycle.recv().await {
+                    break;
+                }
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    async fn rooms_empty(&self) -> bool {
+        let rooms = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for room in rooms {
+            if !room.participants.lock().await.is_empty() {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Закрывает все peer connection, записи комнат завершаются
+    pub async fn close_all_rooms(&self) {
+        let room_ids = self.rooms.lock().await.keys().cloned().collect::<Vec<_>>();
+        for room_id in room_ids {
+            self.close_room(&room_id).await;
+        }
+    }
+
+    // Закрывает соединение участника, дальнейшую очистку делает обработчик смены состояния
+    pub async fn leave(&self, session_id: &str, room_id: &str) -> Result<()> {
+        let Some(room) = self.rooms.lock().await.get(room_id).cloned() else {
+            return Ok(());
+        };
+
+        let Some(peer) = room.participants.lock().await.get(session_id).cloned() else {
+            return Ok(());
+        };
+
+        info!(user:? = session_id, room:? = room_id; "Peer leaves room");
+        peer.pc.close().await?;
+
+        Ok(())
+    }
+
     pub(crate) async fn accept_answer(
         &self,
         session_id: String,
@@ -362,7 +1365,7 @@ impl Sfu {
             bail!("room not found")
         };
 
-        let Some(peer) = room.lock().await.get(&session_id).cloned() else {
+        let Some(peer) = room.participants.lock().await.get(&session_id).cloned() else {
             bail!("No peer found for this session")
         };
 
@@ -396,7 +1399,9 @@ impl Sfu {
         room_id: String,
         candidate: RTCIceCandidateInit,
     ) -> Result<()> {
-        let peer = self.get_or_create_peer(session_id.clone(), room_id).await?;
+        let peer = self
+            .get_or_create_peer(session_id.clone(), room_id, Role::Member)
+            .await?;
 
         match peer.pc.add_ice_candidate(candidate.clone()).await {
             Ok(_) => {
@@ -429,54 +1434,455 @@ impl Sfu {
         }
     }
 
-    async fn send_track_to_participant(&self, track: Arc<TrackRemote>, dist: Arc<Participant>) {
+    async fn send_track_to_participant(
+        &self,
+        forwarder: Arc<TrackForwarder>,
+        dist: Arc<Participant>,
+    ) {
+        let track = forwarder.track();
         let dist_track = Arc::new(TrackLocalStaticRTP::new(
             track.codec().capability,
             track.id() + "-to-" + dist.session_id.as_str(),
-            track.id(),
+            track.stream_id(),
         ));
 
-        let dist_track2 = Arc::clone(&dist_track);
-        dist.pc.add_track(dist_track2).await.unwrap();
+        let sender = if dist.role == Role::Viewer {
+            let slot = dist
+                .slots
+                .lock()
+                .await
+                .attach(forwarder.publisher(), Arc::clone(&dist_track))
+                .await;
+            match slot {
+                Some(sender) => sender,
+                None => {
+                    info!(user:? = dist.session_id, track:? = track.id(); "No viewer slot for track");
+                    return;
+                }
+            }
+        } else {
+            match dist.pc.add_track(Arc::clone(&dist_track) as _).await {
+                Ok(sender) => sender,
+                Err(e) => {
+                    error!(user:? = dist.session_id.clone(), err:? = e; "Failed to add track");
+                    return;
+                }
+            }
+        };
+        dist.senders
+            .lock()
+            .await
+            .entry(forwarder.publisher().to_string())
+            .or_default()
+            .push(Arc::clone(&sender));
+
+        // Ключевой кадр для нового подписчика запрашивается по его первому RTCP
+        forwarder.spawn_rtcp_reader(&dist, sender);
+        forwarder
+            .subscribe(dist.session_id.clone(), dist_track)
+            .await;
+
+        // Слот наблюдателя WHEP уже согласован в answer
+        if dist.role != Role::Member {
+            return;
+        }
 
         let dist2 = Arc::clone(&dist);
         if let Err(e) = self.on_negotiation_needed(Arc::clone(&dist)).await {
             error!(user:? = dist2.session_id.clone(), err:? = e; "Failed await negotiation_needed");
+        }
+    }
+
+    // Качество simulcast слоев, которые участник получает от издателя
+    pub async fn set_layer_quality(
+        &self,
+        session_id: &str,
+        publisher_id: &str,
+        quality: LayerQuality,
+    ) {
+        let forwarders = self.remote_tracks.lock().await.tracks_of(publisher_id);
+
+        for forwarder in forwarders {
+            forwarder.set_quality(session_id, quality).await;
+        }
+    }
+
+    // Издатель выключил или включил свой трек. Выключенный трек не пересылается,
+    // комната получает событие, чтобы показать заглушку вместо застывшего кадра.
+    pub async fn set_track_muted(
+        &self,
+        session_id: &str,
+        room_id: &str,
+        track_id: &str,
+        muted: bool,
+    ) -> Result<()> {
+        if self.find_peer(session_id, room_id).await.is_none() {
+            bail!("No peer found for this session")
+        }
+
+        let Some(forwarder) = self
+            .remote_tracks
+            .lock()
+            .await
+            .tracks_of(session_id)
+            .into_iter()
+            .find(|forwarder| forwarder.track().id() == track_id)
+        else {
+            bail!("track not found")
+        };
+
+        if !forwarder.set_muted(muted) {
+            return Ok(());
+        }
+        info!(user:? = session_id, room:? = room_id, track:? = track_id, muted:? = muted; "Track mute changed");
+
+        self.send_to_members(Some(room_id), track_muted(&forwarder))
+            .await;
+
+        if let (Some(room), Some(peer)) = (
+            self.rooms.lock().await.get(room_id).cloned(),
+            self.find_peer(session_id, room_id).await,
+        ) {
+            self.on_participant_updated(&peer, &room).await;
+        }
+        Ok(())
+    }
+
+    async fn roster_entry(&self, participant: &Participant) -> RosterEntry {
+        let tracks = self
+            .remote_tracks
+            .lock()
+            .await
+            .tracks_of(&participant.session_id);
+
+        RosterEntry {
+            session_id: participant.session_id.clone(),
+            user_id: participant.user_id,
+            display_name: participant.display_name.lock().await.clone(),
+            joined_at: participant.joined_at,
+            tracks: tracks
+                .iter()
+                .map(|forwarder| MediaState::of(forwarder))
+                .collect(),
+        }
+    }
+
+    // Участники с сигналингом, которые уже подключались, в порядке входа
+    async fn roster(&self, room: &Room) -> Vec<RosterEntry> {
+        let participants = room
+            .participants
+            .lock()
+            .await
+            .values()
+            .filter(|participant| {
+                participant.role == Role::Member && participant.connected.load(Ordering::Relaxed)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut roster = vec![];
+        for participant in participants {
+            roster.push(self.roster_entry(&participant).await);
+        }
+        roster.sort_by_key(|entry| entry.joined_at);
+        roster
+    }
+
+    async fn viewers(&self, room: &Room) -> Vec<ViewerEntry> {
+        let mut viewers = room
+            .participants
+            .lock()
+            .await
+            .values()
+            .filter(|participant| participant.role == Role::Viewer)
+            .map(|participant| viewer_entry(participant))
+            .collect::<Vec<_>>();
+        viewers.sort_by_key(|entry| entry.joined_at);
+        viewers
+    }
+
+    async fn send_roster(&self, session_id: &str, room: &Room) {
+        let event = RoomEvent::Roster {
+            participants: self.roster(room).await,
+            viewers: self.viewers(room).await,
+            recording: room.recording.lock().await.is_some(),
+        };
+        if let Err(e) = self
+            .signalling
+            .send_room_event(session_id.to_string(), event)
+            .await
+        {
+            warn!(user:? = session_id, err:? = e; "Could not send roster");
+        }
+    }
+
+    // Событие всем участникам с сигналингом, кроме session_id
+    async fn send_to_others(&self, room: &Room, session_id: &str, event: RoomEvent) {
+        let others = self
+            .members(Some(&room.id))
+            .await
+            .into_iter()
+            .filter(|member| member != session_id);
+        for member in others {
+            if let Err(e) = self
+                .signalling
+                .send_room_event(member.clone(), event.clone())
+                .await
+            {
+                warn!(user:? = member, err:? = e, event:? = event; "Could not send room event");
+            }
+        }
+    }
+
+    // Участник опубликовал трек или изменил его состояние
+    async fn on_participant_updated(&self, participant: &Participant, room: &Room) {
+        if participant.role != Role::Member || !participant.connected.load(Ordering::Relaxed) {
             return;
         }
 
-        // print!("send rtp to {} -> {}", &session_id3, &other_session);
-        // Read RTP packets being sent to webrtc-rs
+        let event = RoomEvent::ParticipantUpdated {
+            participant: self.roster_entry(participant).await,
+        };
+        self.send_to_others(room, &participant.session_id, event)
+            .await;
+    }
 
-        let dist_track = Arc::downgrade(&dist_track);
-        while let Ok((rtp, _)) = track.read_rtp().await {
-            if let Some(dist_track) = dist_track.upgrade() {
-                if let Err(err) = dist_track.write_rtp(&rtp).await {
-                    if Error::ErrClosedPipe != err {
-                        warn!(err:? = err; "output track write_rtp got error and break");
-                        break;
-                    } else {
-                        warn!(err:? = err; "output track write_rtp got error");
-                    }
-                }
-            } else {
-                break;
+    // Подписчик приостанавливает видео издателя, например пока его плитка не видна
+    pub async fn set_video_paused(&self, session_id: &str, publisher_id: &str, paused: bool) {
+        let forwarders = self.remote_tracks.lock().await.tracks_of(publisher_id);
+
+        for forwarder in forwarders {
+            if forwarder.track().kind() == RTPCodecType::Video {
+                forwarder.set_paused(session_id, paused).await;
             }
         }
+        info!(user:? = session_id, publisher:? = publisher_id, paused:? = paused; "Video pause changed");
+    }
 
-        info!("Track sending finished");
+    // Треки всех участников комнаты
+    async fn room_tracks(&self, room: &Room) -> Vec<Arc<TrackForwarder>> {
+        let participants = room
+            .participants
+            .lock()
+            .await
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut remote_tracks = self.remote_tracks.lock().await;
+        participants
+            .iter()
+            .flat_map(|session_id| remote_tracks.tracks_of(session_id))
+            .collect()
     }
 
-    async fn on_connected(
-        &self,
-        new_peer: Arc<Participant>,
-        room: Arc<Mutex<HashMap<String, Arc<Participant>>>>,
-    ) {
+    // Есть ли в комнате websocket сессия пользователя. Потоки WHIP и наблюдатели WHEP не в счет.
+    async fn is_member(&self, room: &Room, user_id: i64) -> bool {
+        room.participants
+            .lock()
+            .await
+            .values()
+            .any(|participant| participant.user_id == user_id && participant.role == Role::Member)
+    }
+
+    // Закрывает соединения пользователя с сигналингом, кроме сессии except
+    pub async fn close_user_sessions(&self, user_id: i64, except: &str) {
+        let peers = self
+            .participants
+            .lock()
+            .await
+            .values()
+            .filter(|p| p.user_id == user_id && p.role == Role::Member && p.session_id != except)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for peer in peers {
+            info!(user:? = user_id, session_id:? = peer.session_id; "Session replaced by a new one");
+            if let Err(e) = peer.pc.close().await {
+                warn!(session_id:? = peer.session_id, err:? = e; "Could not close replaced session");
+            }
+        }
+    }
+
+    // Начинает запись комнаты, записать комнату может только ее участник с websocket сигналингом
+    pub async fn start_recording(&self, user_id: i64, room_id: &str) -> Result<String> {
+        let Some(storage) = self.recordings.clone() else {
+            bail!(RecordingError::Disabled)
+        };
+        let Some(room) = self.rooms.lock().await.get(room_id).cloned() else {
+            bail!("room not found")
+        };
+        if !self.is_member(&room, user_id).await {
+            bail!(RecordingError::NotInRoom)
+        }
+
+        let recording = {
+            let mut current = room.recording.lock().await;
+            if current.is_some() {
+                bail!(RecordingError::AlreadyStarted)
+            }
+            let recording = Arc::new(Recording::new(storage, room_id, &user_id.to_string()));
+            *current = Some(Arc::clone(&recording));
+            recording
+        };
+        recording.save().await?;
+        info!(recording:? = recording.id, room:? = room_id, user:? = user_id; "Recording started");
+
+        for forwarder in self.room_tracks(&room).await {
+            forwarder.start_recording(&recording).await;
+        }
+
+        let started = RoomEvent::RecordingStarted {
+            recording_id: recording.id.clone(),
+            started_by: user_id,
+        };
+        self.send_to_members(Some(room_id), started).await;
+
+        Ok(recording.id.clone())
+    }
+
+    pub async fn stop_recording(&self, user_id: i64, room_id: &str) -> Result<RecordingMetadata> {
+        let Some(room) = self.rooms.lock().await.get(room_id).cloned() else {
+            bail!("room not found")
+        };
+        if !self.is_member(&room, user_id).await {
+            bail!(RecordingError::NotInRoom)
+        }
+
+        let Some(recording) = room.recording.lock().await.take() else {
+            bail!(RecordingError::NotStarted)
+        };
+
+        for forwarder in self.room_tracks(&room).await {
+            forwarder.stop_recording().await;
+        }
+
+        let stopped = RoomEvent::RecordingStopped {
+            recording_id: recording.id.clone(),
+        };
+        self.send_to_members(Some(room_id), stopped).await;
+
+        recording.finish().await
+    }
+
+    // Отписывает участника от всех треков, которые ему пересылались
+    async fn unsubscribe_all(&self, session_id: &str) {
+        let forwarders = self.remote_tracks.lock().await.all();
+
+        for forwarder in forwarders {
+            forwarder.unsubscribe(session_id).await;
+        }
+    }
+
+    // Удаляет у оставшихся участников треки ушедшего и запускает перепереговоры
+    async fn on_participant_left(&self, peer: &Participant, room: Arc<Room>) {
+        let session_id = peer.session_id.as_str();
+        let talk = room.speakers.lock().await.remove(session_id);
+        if let Some(talk) = talk {
+            self.on_talk_finished(talk.summary(&room.id, session_id, peer.user_id))
+                .await;
+        }
+
+        let participants = room
+            .participants
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for participant in participants {
+            let senders = participant
+                .senders
+                .lock()
+                .await
+                .remove(session_id)
+                .unwrap_or_default();
+
+            // Слоты наблюдателя остаются в согласованном SDP и ждут следующий трек
+            if participant.role == Role::Viewer {
+                participant.slots.lock().await.release(session_id).await;
+                continue;
+            }
+
+            for sender in &senders {
+                if let Err(e) = participant.pc.remove_track(sender).await {
+                    warn!(user:? = participant.session_id, err:? = e; "Failed to remove track");
+                }
+            }
+
+            // Участникам WHIP и WHEP события некуда отправить
+            if participant.role != Role::Member {
+                continue;
+            }
+
+            let left = match peer.role {
+                Role::Viewer => RoomEvent::ViewerLeft {
+                    session_id: session_id.to_string(),
+                },
+                _ => RoomEvent::ParticipantLeft {
+                    session_id: session_id.to_string(),
+                },
+            };
+            if let Err(e) = self
+                .signalling
+                .send_room_event(participant.session_id.clone(), left)
+                .await
+            {
+                warn!(user:? = participant.session_id, err:? = e; "Could not send participant left");
+            }
+
+            if senders.is_empty()
+                || participant.pc.connection_state() != RTCPeerConnectionState::Connected
+            {
+                continue;
+            }
+
+            let this = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = this.on_negotiation_needed(Arc::clone(&participant)).await {
+                    error!(user:? = participant.session_id, err:? = e; "Failed await negotiation_needed");
+                }
+            });
+        }
+    }
+
+    async fn on_connected(&self, new_peer: Arc<Participant>, room: Arc<Room>) {
+        // Издатель WHIP ничего не получает, наблюдатель WHEP получает треки в слоты
+        if new_peer.role != Role::Member {
+            return;
+        }
         let session_id = new_peer.session_id.clone();
 
+        // Имя приходит отдельным participant_updated, медленный account не задерживает медиа
+        if let Some(directory) = self.directory.clone() {
+            let this = self.clone();
+            let peer = Arc::clone(&new_peer);
+            let room = Arc::clone(&room);
+            tokio::spawn(async move {
+                match directory.display_name(peer.user_id.to_string()).await {
+                    Ok(Some(name)) => {
+                        *peer.display_name.lock().await = Some(name);
+                        this.on_participant_updated(&peer, &room).await;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!(user:? = peer.session_id, err:? = e; "Could not load display name")
+                    }
+                }
+            });
+        }
+
+        // Список участников приходит раньше их треков
+        self.send_roster(&session_id, &room).await;
+        let joined = RoomEvent::ParticipantJoined {
+            participant: self.roster_entry(&new_peer).await,
+        };
+        self.send_to_others(&room, &session_id, joined).await;
+
         // 1. Получаем список участников (без блокировки всей комнаты)
         let participants = {
-            let room = room.lock().await;
+            let room = room.participants.lock().await;
             room.iter()
                 .filter(|(_, p)| {
                     p.session_id != session_id
@@ -486,66 +1892,413 @@ impl Sfu {
                 .collect::<Vec<_>>()
         };
 
-        let mut remote_tracks = self.remote_tracks.lock().await;
+        // Опоздавший участник получает все треки каждого участника: аудио, видео, экран
+        let forwarders = {
+            let mut remote_tracks = self.remote_tracks.lock().await;
+            participants
+                .iter()
+                .flat_map(|(_, participant)| remote_tracks.tracks_of(&participant.session_id))
+                .collect::<Vec<_>>()
+        };
 
-        let tracks = participants
+        // и узнает, какие из них выключены
+        let muted = forwarders
             .iter()
-            .map(|(_, participant)| {
-                (
-                    participant.session_id.clone(),
-                    remote_tracks
-                        .get(&participant.session_id)
-                        .and_then(|t| t.upgrade()),
-                )
-            })
+            .filter(|forwarder| forwarder.is_muted())
+            .map(|forwarder| track_muted(forwarder))
             .collect::<Vec<_>>();
+        for event in muted {
+            if let Err(e) = self
+                .signalling
+                .send_room_event(session_id.clone(), event)
+                .await
+            {
+                warn!(user:? = session_id, err:? = e; "Could not send track mute state");
+            }
+        }
 
-        for (session_id, track) in tracks {
+        for forwarder in forwarders {
             let this = self.clone();
             let new_peer = new_peer.clone();
 
-            match track {
-                Some(track) => {
-                    tokio::spawn(async move {
-                        // работает до тех пор, пока удается взять weak pointer от track
-                        this.send_track_to_participant(track, new_peer).await;
-                    });
+            tokio::spawn(async move {
+                this.send_track_to_participant(forwarder, new_peer).await;
+            });
+        }
+
+        let dominant = room.speakers.lock().await.dominant().map(str::to_string);
+        if let Some(dominant) = dominant {
+            let event = RoomEvent::DominantSpeakerChanged {
+                session_id: dominant,
+            };
+            if let Err(e) = self
+                .signalling
+                .send_room_event(session_id.clone(), event)
+                .await
+            {
+                warn!(user:? = session_id, err:? = e; "Could not send dominant speaker");
+            }
+        }
+    }
+
+    async fn on_talk_finished(&self, summary: TalkSummary) {
+        info!(user:? = summary.session_id, room:? = summary.room_id, speaking_ms:? = summary.speaking_ms, listening_ms:? = summary.listening_ms, turns:? = summary.turns; "Talk finished");
+
+        {
+            let mut summaries = self.talk_summaries.lock().await;
+            summaries.retain(|_, (finished_at, _)| finished_at.elapsed() < TALK_SUMMARY_TTL);
+            summaries.insert(summary.user_id, (Instant::now(), summary.clone()));
+        }
+
+        if let Some(history) = self.history.clone() {
+            tokio::spawn(async move {
+                let session_id = summary.session_id.clone();
+                if let Err(e) = history.save(summary).await {
+                    warn!(user:? = session_id, err:? = e; "Could not save talk summary");
                 }
-                None => {
-                    remote_tracks.remove(&session_id);
+            });
+        }
+    }
+
+    // Статистика последнего завершенного разговора пользователя в любой из его сессий
+    pub async fn talk_summary(&self, user_id: i64) -> Option<TalkSummary> {
+        self.talk_summaries
+            .lock()
+            .await
+            .get(&user_id)
+            .filter(|(finished_at, _)| finished_at.elapsed() < TALK_SUMMARY_TTL)
+            .map(|(_, summary)| summary.clone())
+    }
+
+    // Раз в SPEAKER_INTERVAL пересчитывает основного говорящего в каждой комнате
+    pub fn spawn_speaker_detection(&self) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SPEAKER_INTERVAL);
+            loop {
+                interval.tick().await;
+
+                let rooms = this
+                    .rooms
+                    .lock()
+                    .await
+                    .values()
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for room in rooms {
+                    let dominant = room.speakers.lock().await.tick(SPEAKER_INTERVAL);
+                    if let Some(dominant) = dominant {
+                        this.on_dominant_speaker_changed(&room, dominant).await;
+                    }
                 }
+            }
+        });
+    }
+
+    async fn on_dominant_speaker_changed(&self, room: &Room, dominant: String) {
+        info!(room:? = room.id, user:? = dominant; "Dominant speaker changed");
+
+        let participants = room
+            .participants
+            .lock()
+            .await
+            .values()
+            .filter(|participant| participant.role == Role::Member)
+            .map(|participant| participant.session_id.clone())
+            .collect::<Vec<_>>();
+        for session_id in participants {
+            let event = RoomEvent::DominantSpeakerChanged {
+                session_id: dominant.clone(),
             };
+            if let Err(e) = self
+                .signalling
+                .send_room_event(session_id.clone(), event)
+                .await
+            {
+                warn!(user:? = session_id, err:? = e; "Could not send dominant speaker");
+            }
+        }
+    }
+}
+
+fn track_muted(forwarder: &TrackForwarder) -> RoomEvent {
+    RoomEvent::TrackMuted {
+        session_id: forwarder.publisher().to_string(),
+        track_id: forwarder.track().id(),
+        kind: kind_label(forwarder.track().kind()).to_string(),
+        muted: forwarder.is_muted(),
+    }
+}
+
+async fn send_chat(channel: &RTCDataChannel, message: &ChatServerMessage<'_>) {
+    let message = match serde_json::to_string(message) {
+        Ok(message) => message,
+        Err(e) => {
+            error!(err:? = e; "Could not serialize chat message");
+            return;
         }
+    };
+
+    if let Err(e) = channel.send_text(message).await {
+        warn!(err:? = e; "Could not send chat message");
     }
 }
 
-pub async fn create_peer(session_id: String) -> webrtc::error::Result<RTCPeerConnection> {
-    let mut m = MediaEngine::default();
-    m.register_default_codecs()?;
-    let mut registry = Registry::new();
-    registry = register_default_interceptors(registry, &mut m)?;
-    let api = APIBuilder::new()
-        .with_media_engine(m)
-        .with_interceptor_registry(registry)
-        .build();
+fn viewer_entry(viewer: &Participant) -> ViewerEntry {
+    ViewerEntry {
+        session_id: viewer.session_id.clone(),
+        user_id: viewer.user_id,
+        joined_at: viewer.joined_at,
+    }
+}
 
+pub async fn create_peer(
+    api: &API,
+    session_id: String,
+    ice_servers: Vec<RTCIceServer>,
+) -> webrtc::error::Result<RTCPeerConnection> {
     // Prepare the configuration
     let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            urls: vec![
-                "stun:stun.l.google.com:19302".to_owned(),
-                "stun:stun.l.google.com:5349".to_owned(),
-                "stun:stun1.l.google.com:3478".to_owned(),
-                "stun:stun1.l.google.com:5349".to_owned(),
-                "stun:stun2.l.google.com:19302".to_owned(),
-                "stun:stun.sipnet.net:3478".to_owned(),
-                "stun:stun.sipnet.ru:3478".to_owned(),
-                "stun:stun.stunprotocol.org:3478".to_owned(),
-            ],
-            ..Default::default()
-        }],
+        ice_servers,
         peer_identity: session_id.clone(),
         ..Default::default()
     };
     api.new_peer_connection(config).await
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::webrtc::codec::{CodecPolicy, VideoCodec};
+    use crate::webrtc::ice::IceConfig;
+    use crate::webrtc::recording::LocalStorage;
+
+    // Сигналинг, который запоминает отправленное участникам
+    #[derive(Default)]
+    pub(crate) struct Recorder {
+        offers: std::sync::Mutex<Vec<String>>,
+        events: std::sync::Mutex<Vec<(String, RoomEvent)>>,
+        pub(crate) matchmaking: std::sync::Mutex<Vec<(String, MatchmakingEvent)>>,
+    }
+
+    impl Recorder {
+        fn rosters(&self, session_id: &str) -> usize {
+            self.events
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(to, event)| to == session_id && matches!(event, RoomEvent::Roster { .. }))
+                .count()
+        }
+    }
+
+    impl Signalling for Recorder {
+        fn send_sdp(
+            &self,
+            session_id: String,
+            _: RTCSessionDescription,
+        ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+            self.offers.lock().unwrap().push(session_id);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn send_ice_candidate(
+            &self,
+            _: String,
+            _: Option<RTCIceCandidate>,
+        ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn send_matchmaking(
+            &self,
+            session_id: String,
+            event: MatchmakingEvent,
+        ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+            self.matchmaking.lock().unwrap().push((session_id, event));
+            Box::pin(async { Ok(()) })
+        }
+
+        fn send_room_event(
+            &self,
+            session_id: String,
+            event: RoomEvent,
+        ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+            self.events.lock().unwrap().push((session_id, event));
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    pub(crate) fn sfu(signalling: &Arc<Recorder>, reconnect_window: Duration) -> Sfu {
+        Sfu::new(
+            Arc::clone(signalling) as _,
+            config(reconnect_window),
+            None,
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    fn config(reconnect_window: Duration) -> SfuConfig {
+        SfuConfig {
+            room_capacity: None,
+            empty_room_ttl: Duration::from_secs(60),
+            ice: IceConfig {
+                stun_urls: vec![],
+                turn_urls: vec![],
+                turn_credentials: None,
+            },
+            codecs: CodecPolicy {
+                video: vec![VideoCodec::Vp8],
+                opus_fec: true,
+                opus_dtx: false,
+            },
+            chat_history: 0,
+            reconnect_window,
+        }
+    }
+
+    async fn member(sfu: &Sfu) -> (Arc<Participant>, Arc<Room>) {
+        let session_id = session::new_session_id(1, session::WS);
+        let peer = sfu
+            .get_or_create_peer(session_id, "room".to_string(), Role::Member)
+            .await
+            .unwrap();
+        // Первый offer создает ICE агент, перезапустить его можно после сбора кандидатов
+        let mut gather_complete = peer.pc.gathering_complete_promise().await;
+        sfu.on_negotiation_needed(Arc::clone(&peer)).await.unwrap();
+        let _ = gather_complete.recv().await;
+        let room = sfu.rooms.lock().await.get("room").cloned().unwrap();
+        (peer, room)
+    }
+
+    // Ждет, пока условие станет верным, фоновые задачи SFU выполняются асинхронно
+    async fn eventually(mut condition: impl AsyncFnMut() -> bool) -> bool {
+        for _ in 0..100 {
+            if condition().await {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn resume_after_ice_restart() {
+        let signalling = Arc::new(Recorder::default());
+        let sfu = sfu(&signalling, Duration::from_millis(200));
+        let (peer, room) = member(&sfu).await;
+        let session_id = peer.session_id.clone();
+
+        sfu.on_peer_state(
+            Arc::clone(&peer),
+            Arc::clone(&room),
+            RTCPeerConnectionState::Connected,
+        )
+        .await;
+        assert_eq!(signalling.rosters(&session_id), 1);
+
+        sfu.on_peer_state(
+            Arc::clone(&peer),
+            Arc::clone(&room),
+            RTCPeerConnectionState::Disconnected,
+        )
+        .await;
+        assert!(peer.disconnected_at.lock().await.is_some());
+        // SFU отправляет второй offer, с перезапуском ICE
+        assert!(eventually(async || signalling.offers.lock().unwrap().len() == 2).await);
+
+        sfu.on_peer_state(
+            Arc::clone(&peer),
+            Arc::clone(&room),
+            RTCPeerConnectionState::Connected,
+        )
+        .await;
+        assert!(peer.disconnected_at.lock().await.is_none());
+        // После восстановления участник заново получает список комнаты
+        assert_eq!(signalling.rosters(&session_id), 2);
+
+        // Окно переподключения прошло, но участник уже вернулся и остается в комнате
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_ne!(peer.pc.connection_state(), RTCPeerConnectionState::Closed);
+        assert!(sfu.participants.lock().await.contains_key(&session_id));
+    }
+
+    #[tokio::test]
+    async fn only_members_control_recording() {
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let signalling = Arc::new(Recorder::default());
+        let sfu = Sfu::new(
+            Arc::clone(&signalling) as _,
+            config(Duration::from_secs(15)),
+            None,
+            Some(Arc::new(LocalStorage::new(root.clone()))),
+            None,
+        )
+        .unwrap();
+        let _ = member(&sfu).await;
+        sfu.get_or_create_peer(
+            session::new_session_id(2, session::WHEP),
+            "room".to_string(),
+            Role::Viewer,
+        )
+        .await
+        .unwrap();
+        sfu.get_or_create_peer(
+            session::new_session_id(3, session::WHIP),
+            "room".to_string(),
+            Role::Ingest,
+        )
+        .await
+        .unwrap();
+
+        for user_id in [2, 3] {
+            let err = sfu.start_recording(user_id, "room").await.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<RecordingError>(),
+                Some(RecordingError::NotInRoom)
+            ));
+        }
+        sfu.start_recording(1, "room").await.unwrap();
+        for user_id in [2, 3] {
+            let err = sfu.stop_recording(user_id, "room").await.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<RecordingError>(),
+                Some(RecordingError::NotInRoom)
+            ));
+        }
+        sfu.stop_recording(1, "room").await.unwrap();
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn close_after_reconnect_window() {
+        let signalling = Arc::new(Recorder::default());
+        let sfu = sfu(&signalling, Duration::from_millis(50));
+        let (peer, room) = member(&sfu).await;
+        let session_id = peer.session_id.clone();
+
+        sfu.on_peer_state(
+            Arc::clone(&peer),
+            Arc::clone(&room),
+            RTCPeerConnectionState::Connected,
+        )
+        .await;
+        sfu.on_peer_state(
+            Arc::clone(&peer),
+            Arc::clone(&room),
+            RTCPeerConnectionState::Failed,
+        )
+        .await;
+        assert!(sfu.in_reconnect_window(&session_id).await);
+
+        // Участник не вернулся: соединение закрыто и участник удален из комнаты
+        assert!(
+            eventually(async || !sfu.participants.lock().await.contains_key(&session_id)).await
+        );
+        assert_eq!(peer.pc.connection_state(), RTCPeerConnectionState::Closed);
+        assert!(!room.participants.lock().await.contains_key(&session_id));
+    }
+}
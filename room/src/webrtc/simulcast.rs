@@ -0,0 +1,364 @@
+use serde::{Deserialize, Serialize};
+use webrtc::api::media_engine::{MIME_TYPE_AV1, MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9};
+use webrtc::rtp::header::Header;
+
+const PAUSE_MARGIN: f64 = 2.0;
+
+// Разница временных меток между последним кадром старого слоя и первым кадром нового (~1 кадр при 30 fps)
+const SWITCH_TIMESTAMP_GAP: u32 = 90000 / 30;
+
+// Качество, которое подписчик хочет получать от simulcast издателя
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerQuality {
+    Low,
+    Medium,
+    #[default]
+    High,
+}
+
+// Выбирает rid слоя по качеству. Имена rid у браузеров разные ("q/h/f", "l/m/h", "0/1/2"),
+// поэтому слои упорядочиваются по текущему битрейту. Неактивные слои (битрейт 0) не выбираются,
+// пока есть хоть один активный.
+// Если задан бюджет канала подписчика, выбирается слой не выше бюджета. None - видео нужно
+// приостановить: даже нижний слой больше бюджета в PAUSE_MARGIN раз.
+pub fn select_layer(
+    layers: &[(String, u64)],
+    quality: LayerQuality,
+    budget: Option<u64>,
+) -> Option<&str> {
+    let mut active = layers
+        .iter()
+        .filter(|(_, bitrate)| *bitrate > 0)
+        .collect::<Vec<_>>();
+    if active.is_empty() {
+        active = layers.iter().collect();
+    }
+    active.sort_by_key(|(_, bitrate)| *bitrate);
+
+    let mut index = match quality {
+        LayerQuality::Low => 0,
+        LayerQuality::Medium => active.len() / 2,
+        LayerQuality::High => active.len().checked_sub(1)?,
+    };
+
+    if let Some(budget) = budget {
+        while index > 0 && active[index].1 > budget {
+            index -= 1;
+        }
+        // Нижний слой немного больше бюджета лучше паузы: издатель получает REMB и сам снизит битрейт
+        if active.get(index)?.1 as f64 > budget as f64 * PAUSE_MARGIN {
+            return None;
+        }
+    }
+
+    active.get(index).map(|(rid, _)| rid.as_str())
+}
+
+// Начинается ли с этого пакета ключевой кадр. Переключать слой можно только на ключевом кадре,
+// иначе декодер подписчика получит дельта-кадры без опорного.
+pub fn is_keyframe(mime_type: &str, payload: &[u8]) -> bool {
+    if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+        vp8_keyframe(payload)
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+        vp9_keyframe(payload)
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+        h264_keyframe(payload)
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_AV1) {
+        av1_keyframe(payload)
+    } else {
+        false
+    }
+}
+
+// RFC 7741
+fn vp8_keyframe(payload: &[u8]) -> bool {
+    let Some(&first) = payload.first() else {
+        return false;
+    };
+    let (extended, start, partition) = (first & 0x80 != 0, first & 0x10 != 0, first & 0x07);
+    if !start || partition != 0 {
+        return false;
+    }
+
+    let mut offset = 1;
+    if extended {
+        let Some(&x) = payload.get(offset) else {
+            return false;
+        };
+        offset += 1;
+        if x & 0x80 != 0 {
+            // PictureID, 7 или 15 бит
+            let long = payload.get(offset).is_some_and(|b| b & 0x80 != 0);
+            offset += if long { 2 } else { 1 };
+        }
+        if x & 0x40 != 0 {
+            offset += 1; // TL0PICIDX
+        }
+        if x & 0x30 != 0 {
+            offset += 1; // TID/KEYIDX
+        }
+    }
+
+    // P бит заголовка VP8 кадра: 0 - ключевой кадр
+    payload.get(offset).is_some_and(|b| b & 0x01 == 0)
+}
+
+// draft-ietf-payload-vp9: P=0 (нет ссылок на другие кадры) и B=1 (начало кадра)
+fn vp9_keyframe(payload: &[u8]) -> bool {
+    payload
+        .first()
+        .is_some_and(|b| b & 0x40 == 0 && b & 0x08 != 0)
+}
+
+// RFC 6184: IDR или SPS, в том числе внутри STAP-A и в начале FU-A
+fn h264_keyframe(payload: &[u8]) -> bool {
+    let Some(&first) = payload.first() else {
+        return false;
+    };
+    let is_key = |nal: u8| matches!(nal & 0x1f, 5 | 7);
+
+    match first & 0x1f {
+        24 => {
+            let mut offset = 1;
+            while offset + 2 < payload.len() {
+                let size = u16::from_be_bytes([payload[offset], payload[offset + 1]]) as usize;
+                if is_key(payload[offset + 2]) {
+                    return true;
+                }
+                offset += 2 + size;
+            }
+            false
+        }
+        28 => payload.get(1).is_some_and(|b| b & 0x80 != 0 && is_key(*b)),
+        _ => is_key(first),
+    }
+}
+
+// AV1 RTP spec: N бит заголовка агрегации - первый пакет новой видеопоследовательности
+fn av1_keyframe(payload: &[u8]) -> bool {
+    payload.first().is_some_and(|b| b & 0x08 != 0)
+}
+
+// Переписывает номера пакетов и временные метки, чтобы у подписчика был один непрерывный поток,
+// из какого бы слоя ни приходили пакеты. SSRC подставляет сам TrackLocalStaticRTP.
+#[derive(Debug, Default)]
+pub struct RtpRewriter {
+    sequence_offset: u16,
+    timestamp_offset: u32,
+    last: Option<(u16, u32)>,
+    switching: bool,
+}
+
+impl RtpRewriter {
+    // Следующий пакет придет из другого слоя
+    pub fn switch(&mut self) {
+        self.switching = true;
+    }
+
+    pub fn rewrite(&mut self, header: &mut Header) {
+        if self.switching {
+            self.switching = false;
+            if let Some((sequence_number, timestamp)) = self.last {
+                self.sequence_offset = header
+                    .sequence_number
+                    .wrapping_sub(sequence_number.wrapping_add(1));
+                self.timestamp_offset = header
+                    .timestamp
+                    .wrapping_sub(timestamp.wrapping_add(SWITCH_TIMESTAMP_GAP));
+            }
+        }
+
+        header.sequence_number = header.sequence_number.wrapping_sub(self.sequence_offset);
+        header.timestamp = header.timestamp.wrapping_sub(self.timestamp_offset);
+
+        // Запоминаем только самый новый пакет, переупорядоченные не сдвигают позицию
+        let newer = self.last.is_none_or(|(sequence_number, _)| {
+            header.sequence_number.wrapping_sub(sequence_number) < 0x8000
+        });
+        if newer {
+            self.last = Some((header.sequence_number, header.timestamp));
+        }
+    }
+}
+
+// Какой слой сейчас получает подписчик и на какой он переключается
+#[derive(Debug, Default)]
+pub struct LayerSelector {
+    pub quality: LayerQuality,
+    // Битрейт, который можно тратить на этот трек, по оценке канала подписчика
+    pub budget: Option<u64>,
+    paused: bool,
+    current: Option<String>,
+    pending: Option<String>,
+    rewriter: RtpRewriter,
+}
+
+impl LayerSelector {
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Канал подписчика не тянет даже нижний слой. Возвращает true, если видео только что остановлено.
+    pub fn pause(&mut self) -> bool {
+        self.current = None;
+        self.pending = None;
+        !std::mem::replace(&mut self.paused, true)
+    }
+
+    // Начинает переключение на target. Возвращает true один раз на переключение,
+    // в этот момент у издателя нужно запросить ключевой кадр слоя target.
+    pub fn retarget(&mut self, target: &str) -> bool {
+        self.paused = false;
+        if self.current.as_deref() == Some(target) {
+            self.pending = None;
+            return false;
+        }
+        if self.pending.as_deref() == Some(target) {
+            return false;
+        }
+
+        self.pending = Some(target.to_string());
+        true
+    }
+
+    // Слой пропал: ждем ключевого кадра другого слоя
+    pub fn drop_layer(&mut self, rid: &str) {
+        if self.current.as_deref() == Some(rid) {
+            self.current = None;
+        }
+        if self.pending.as_deref() == Some(rid) {
+            self.pending = None;
+        }
+    }
+
+    // Нужно ли пересылать пакет слоя rid. Заголовок переписывается под поток подписчика.
+    pub fn accept(&mut self, rid: &str, keyframe: bool, header: &mut Header) -> bool {
+        if keyframe && self.pending.as_deref() == Some(rid) {
+            self.pending = None;
+            self.current = Some(rid.to_string());
+            self.rewriter.switch();
+        }
+
+        if self.current.as_deref() != Some(rid) {
+            return false;
+        }
+
+        self.rewriter.rewrite(header);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(sequence_number: u16, timestamp: u32) -> Header {
+        Header {
+            sequence_number,
+            timestamp,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn select_layer_by_bitrate() {
+        let layers = vec![
+            ("f".to_string(), 1_500_000),
+            ("q".to_string(), 150_000),
+            ("h".to_string(), 500_000),
+        ];
+        assert_eq!(select_layer(&layers, LayerQuality::Low, None), Some("q"));
+        assert_eq!(select_layer(&layers, LayerQuality::Medium, None), Some("h"));
+        assert_eq!(select_layer(&layers, LayerQuality::High, None), Some("f"));
+
+        // бюджет подписчика ограничивает качество
+        assert_eq!(
+            select_layer(&layers, LayerQuality::High, Some(600_000)),
+            Some("h")
+        );
+        assert_eq!(
+            select_layer(&layers, LayerQuality::High, Some(100_000)),
+            Some("q")
+        );
+        assert_eq!(
+            select_layer(&layers, LayerQuality::High, Some(50_000)),
+            None
+        );
+
+        // верхний слой отключен браузером
+        let layers = vec![("f".to_string(), 0), ("q".to_string(), 150_000)];
+        assert_eq!(select_layer(&layers, LayerQuality::High, None), Some("q"));
+        assert_eq!(select_layer(&[], LayerQuality::High, None), None);
+    }
+
+    #[test]
+    fn detect_keyframes() {
+        // VP8: S=1, PID=0, P=0
+        assert!(is_keyframe(MIME_TYPE_VP8, &[0x10, 0x00]));
+        assert!(!is_keyframe(MIME_TYPE_VP8, &[0x10, 0x01]));
+        // VP8 с расширенным заголовком и 15-битным PictureID
+        assert!(is_keyframe(MIME_TYPE_VP8, &[0x90, 0x80, 0x81, 0x23, 0x00]));
+        // H264: IDR, STAP-A с SPS, FU-A начало IDR
+        assert!(is_keyframe(MIME_TYPE_H264, &[0x65]));
+        assert!(is_keyframe(MIME_TYPE_H264, &[0x78, 0x00, 0x02, 0x67, 0x42]));
+        assert!(is_keyframe(MIME_TYPE_H264, &[0x7c, 0x85]));
+        assert!(!is_keyframe(MIME_TYPE_H264, &[0x7c, 0x05]));
+        assert!(!is_keyframe(MIME_TYPE_H264, &[0x41]));
+        // VP9: B=1, P=0
+        assert!(is_keyframe(MIME_TYPE_VP9, &[0x08]));
+        assert!(!is_keyframe(MIME_TYPE_VP9, &[0x48]));
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let mut selector = LayerSelector::default();
+        selector.retarget("");
+        assert!(selector.accept("", true, &mut header(1, 0)));
+
+        assert!(selector.pause());
+        assert!(!selector.pause());
+        assert!(!selector.accept("", false, &mut header(2, 3000)));
+
+        assert!(selector.retarget(""));
+        assert!(!selector.is_paused());
+        let mut h = header(40, 90000);
+        assert!(selector.accept("", true, &mut h));
+        assert_eq!(h.sequence_number, 2);
+    }
+
+    #[test]
+    fn switch_layer_on_keyframe() {
+        let mut selector = LayerSelector::default();
+        assert!(selector.retarget("f"));
+        assert!(!selector.retarget("f"));
+
+        assert!(!selector.accept("f", false, &mut header(10, 1000)));
+        assert!(selector.accept("f", true, &mut header(11, 4000)));
+        assert_eq!(selector.current(), Some("f"));
+
+        let mut h = header(12, 4000);
+        assert!(selector.accept("f", false, &mut h));
+        assert_eq!((h.sequence_number, h.timestamp), (12, 4000));
+
+        // переключение на нижний слой продолжает нумерацию
+        assert!(selector.retarget("q"));
+        assert!(!selector.accept("q", false, &mut header(500, 90000)));
+        let mut h = header(501, 93000);
+        assert!(selector.accept("q", true, &mut h));
+        assert_eq!(h.sequence_number, 13);
+        assert_eq!(h.timestamp, 4000 + SWITCH_TIMESTAMP_GAP);
+        assert!(!selector.accept("f", false, &mut header(13, 7000)));
+
+        let mut h = header(502, 96000);
+        assert!(selector.accept("q", false, &mut h));
+        assert_eq!(
+            (h.sequence_number, h.timestamp),
+            (14, 7000 + SWITCH_TIMESTAMP_GAP)
+        );
+    }
+}
@@ -0,0 +1,332 @@
+use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+// Громче -50 dBov считается речью. Флаг V (voice activity) шлют не все браузеры, поэтому он не учитывается.
+const SPEECH_LEVEL: u8 = 50;
+// Opus шлет пакет каждые 20 мс
+const PACKET_DURATION: Duration = Duration::from_millis(20);
+// Сглаживание оценки: доля нового интервала
+const SMOOTHING: f32 = 0.3;
+// Минимальная оценка, чтобы стать основным говорящим
+const MIN_SCORE: f32 = 0.2;
+// Насколько новый говорящий должен быть активнее текущего, чтобы его сменить
+const HYSTERESIS: f32 = 0.15;
+// Участник говорит, если речь занимает хотя бы такую долю интервала
+const SPEAKING_ACTIVITY: f32 = 0.3;
+// Пауза, после которой реплика считается законченной
+const TURN_GAP: Duration = Duration::from_secs(1);
+
+// Значение расширения urn:ietf:params:rtp-hdrext:ssrc-audio-level (RFC 6464)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioLevel {
+    // 0..127, громкость -dBov: 0 - максимальная, 127 - тишина
+    pub level: u8,
+    pub voice: bool,
+}
+
+impl AudioLevel {
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let byte = payload.first()?;
+        Some(AudioLevel {
+            level: byte & 0x7f,
+            voice: byte & 0x80 != 0,
+        })
+    }
+
+    pub fn is_speech(&self) -> bool {
+        self.level <= SPEECH_LEVEL
+    }
+}
+
+// Сколько участник говорил и слушал за время в комнате
+#[derive(Debug)]
+pub struct TalkStats {
+    started_at: NaiveDateTime,
+    joined: Instant,
+    speaking: Duration,
+    listening: Duration,
+    longest_monologue: Duration,
+    turns: u32,
+    monologue: Duration,
+    silence: Duration,
+    in_turn: bool,
+}
+
+impl TalkStats {
+    fn new() -> Self {
+        TalkStats {
+            started_at: Utc::now().naive_utc(),
+            joined: Instant::now(),
+            speaking: Duration::ZERO,
+            listening: Duration::ZERO,
+            longest_monologue: Duration::ZERO,
+            turns: 0,
+            monologue: Duration::ZERO,
+            silence: Duration::ZERO,
+            in_turn: false,
+        }
+    }
+
+    // others - кто-то из остальных участников говорил в этом интервале
+    fn record(&mut self, interval: Duration, speaking: bool, others: bool) {
+        if speaking {
+            if !self.in_turn {
+                self.in_turn = true;
+                self.turns += 1;
+                self.monologue = Duration::ZERO;
+            }
+            self.speaking += interval;
+            self.monologue += interval;
+            self.longest_monologue = self.longest_monologue.max(self.monologue);
+            self.silence = Duration::ZERO;
+            return;
+        }
+
+        self.silence += interval;
+        if others {
+            self.listening += interval;
+        }
+        // Реплика заканчивается долгой паузой или когда начинает говорить собеседник
+        if others || self.silence >= TURN_GAP {
+            self.in_turn = false;
+        }
+    }
+
+    pub fn summary(&self, room_id: &str, session_id: &str, user_id: i64) -> TalkSummary {
+        let talked = self.speaking + self.listening;
+        let talk_ratio = if talked.is_zero() {
+            0.0
+        } else {
+            self.speaking.as_secs_f32() / talked.as_secs_f32()
+        };
+
+        TalkSummary {
+            room_id: room_id.to_string(),
+            session_id: session_id.to_string(),
+            user_id,
+            started_at: self.started_at,
+            duration_ms: self.joined.elapsed().as_millis() as u64,
+            speaking_ms: self.speaking.as_millis() as u64,
+            listening_ms: self.listening.as_millis() as u64,
+            talk_ratio,
+            longest_monologue_ms: self.longest_monologue.as_millis() as u64,
+            turns: self.turns,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TalkSummary {
+    pub room_id: String,
+    pub session_id: String,
+    pub user_id: i64,
+    pub started_at: NaiveDateTime,
+    pub duration_ms: u64,
+    pub speaking_ms: u64,
+    pub listening_ms: u64,
+    // Доля своей речи среди всей речи, которую участник слышал: 0.5 - говорили поровну
+    pub talk_ratio: f32,
+    pub longest_monologue_ms: u64,
+    pub turns: u32,
+}
+
+#[derive(Debug)]
+struct SpeakerState {
+    speech_packets: u32,
+    score: f32,
+    talk: TalkStats,
+}
+
+impl SpeakerState {
+    fn new() -> Self {
+        SpeakerState {
+            speech_packets: 0,
+            score: 0.0,
+            talk: TalkStats::new(),
+        }
+    }
+}
+
+// Определяет основного говорящего в комнате по уровням звука
+#[derive(Debug, Default)]
+pub struct SpeakerDetector {
+    participants: HashMap<String, SpeakerState>,
+    dominant: Option<String>,
+}
+
+impl SpeakerDetector {
+    // Участник вошел в комнату, время разговора считается с этого момента
+    pub fn join(&mut self, session_id: &str) {
+        self.participants
+            .entry(session_id.to_string())
+            .or_insert_with(SpeakerState::new);
+    }
+
+    // Пакеты ушедшего участника, которые еще в пути, не должны вернуть его в комнату
+    pub fn observe(&mut self, session_id: &str, level: AudioLevel) {
+        let Some(state) = self.participants.get_mut(session_id) else {
+            return;
+        };
+
+        if level.is_speech() {
+            state.speech_packets += 1;
+        }
+    }
+
+    // Итоговая статистика разговора ушедшего участника
+    pub fn remove(&mut self, session_id: &str) -> Option<TalkStats> {
+        if self.dominant.as_deref() == Some(session_id) {
+            self.dominant = None;
+        }
+        self.participants.remove(session_id).map(|state| state.talk)
+    }
+
+    pub fn dominant(&self) -> Option<&str> {
+        self.dominant.as_deref()
+    }
+
+    // Пересчитывает оценки за прошедший интервал. Возвращает нового основного говорящего, если он сменился.
+    // Когда все молчат, основной говорящий не меняется.
+    pub fn tick(&mut self, interval: Duration) -> Option<String> {
+        let expected = (interval.as_millis() / PACKET_DURATION.as_millis()).max(1) as f32;
+
+        let mut speaking = vec![];
+        for (session_id, state) in self.participants.iter_mut() {
+            let activity = (state.speech_packets as f32 / expected).min(1.0);
+            state.score = state.score * (1.0 - SMOOTHING) + activity * SMOOTHING;
+            state.speech_packets = 0;
+
+            if activity >= SPEAKING_ACTIVITY {
+                speaking.push(session_id.clone());
+            }
+        }
+
+        for (session_id, state) in self.participants.iter_mut() {
+            let is_speaking = speaking.contains(session_id);
+            let others = speaking.len() > usize::from(is_speaking);
+            state.talk.record(interval, is_speaking, others);
+        }
+
+        let (candidate, score) = self
+            .participants
+            .iter()
+            .max_by(|(_, a), (_, b)| a.score.total_cmp(&b.score))
+            .map(|(session_id, state)| (session_id.clone(), state.score))?;
+
+        if score < MIN_SCORE || self.dominant.as_ref() == Some(&candidate) {
+            return None;
+        }
+
+        let current = self
+            .dominant
+            .as_ref()
+            .and_then(|session_id| self.participants.get(session_id))
+            .map_or(0.0, |state| state.score);
+        if score < current + HYSTERESIS {
+            return None;
+        }
+
+        self.dominant = Some(candidate.clone());
+        Some(candidate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(300);
+
+    fn speak(detector: &mut SpeakerDetector, session_id: &str, level: u8) {
+        for _ in 0..15 {
+            detector.observe(session_id, AudioLevel { level, voice: true });
+        }
+    }
+
+    #[test]
+    fn parse_audio_level() {
+        assert_eq!(
+            AudioLevel::parse(&[0x80 | 30]),
+            Some(AudioLevel {
+                level: 30,
+                voice: true
+            })
+        );
+        assert_eq!(AudioLevel::parse(&[]), None);
+    }
+
+    #[test]
+    fn dominant_speaker_with_hysteresis() {
+        let mut detector = SpeakerDetector::default();
+        detector.join("a");
+        detector.join("b");
+
+        speak(&mut detector, "a", 30);
+        speak(&mut detector, "b", 100);
+        assert_eq!(detector.tick(INTERVAL), Some("a".to_string()));
+
+        // b начинает говорить, но сменяет a не сразу
+        speak(&mut detector, "a", 30);
+        speak(&mut detector, "b", 30);
+        assert_eq!(detector.tick(INTERVAL), None);
+
+        for _ in 0..5 {
+            speak(&mut detector, "b", 30);
+            detector.tick(INTERVAL);
+        }
+        assert_eq!(detector.dominant(), Some("b"));
+
+        // тишина не сбрасывает основного говорящего
+        for _ in 0..10 {
+            assert_eq!(detector.tick(INTERVAL), None);
+        }
+        assert_eq!(detector.dominant(), Some("b"));
+
+        assert!(detector.remove("b").is_some());
+        assert_eq!(detector.dominant(), None);
+    }
+
+    #[test]
+    fn ignore_removed_participant() {
+        let mut detector = SpeakerDetector::default();
+        detector.join("a");
+        assert!(detector.remove("a").is_some());
+
+        speak(&mut detector, "a", 30);
+        assert_eq!(detector.tick(INTERVAL), None);
+        assert_eq!(detector.dominant(), None);
+        assert!(detector.remove("a").is_none());
+    }
+
+    #[test]
+    fn talk_stats() {
+        let mut detector = SpeakerDetector::default();
+        detector.join("a");
+        detector.join("b");
+
+        // a: 3 интервала речи, b отвечает 2 интервала, a снова 1 интервал
+        for _ in 0..3 {
+            speak(&mut detector, "a", 30);
+            detector.tick(INTERVAL);
+        }
+        for _ in 0..2 {
+            speak(&mut detector, "b", 30);
+            detector.tick(INTERVAL);
+        }
+        speak(&mut detector, "a", 30);
+        detector.tick(INTERVAL);
+
+        let summary = detector.remove("a").unwrap().summary("room", "a", 1);
+        assert_eq!(summary.speaking_ms, 1200);
+        assert_eq!(summary.listening_ms, 600);
+        assert_eq!(summary.longest_monologue_ms, 900);
+        assert_eq!(summary.turns, 2);
+        assert!((summary.talk_ratio - 2.0 / 3.0).abs() < 0.01);
+
+        let summary = detector.remove("b").unwrap().summary("room", "b", 2);
+        assert_eq!(summary.speaking_ms, 600);
+        assert_eq!(summary.turns, 1);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::webrtc::codec::CodecPolicy;
+use anyhow::Result;
+use log::warn;
+use std::sync::Arc;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::TrackLocal;
+
+// Наблюдатель WHEP не поддерживает перепереговоры, поэтому под каждую секцию его offer
+// заранее создается слот. Пустой слот держит заглушку, в которую ничего не пишется,
+// трек издателя ставится в слот через replace_track и снимается, когда издатель уходит.
+pub struct ViewerSlot {
+    sender: Arc<RTCRtpSender>,
+    idle: Arc<TrackLocalStaticRTP>,
+    // session_id издателя, чей трек сейчас в слоте
+    publisher: Option<String>,
+}
+
+#[derive(Default)]
+pub struct ViewerSlots(Vec<ViewerSlot>);
+
+impl ViewerSlots {
+    // Слоты добавляются до answer и занимают секции offer, в которых наблюдатель принимает медиа
+    pub async fn allocate(
+        pc: &RTCPeerConnection,
+        session_id: &str,
+        offer: &RTCSessionDescription,
+        codecs: &CodecPolicy,
+    ) -> Result<Self> {
+        let mut slots = vec![];
+        for (i, kind) in offered_kinds(offer)?.into_iter().enumerate() {
+            let idle = Arc::new(TrackLocalStaticRTP::new(
+                codecs.preferred(kind),
+                format!("idle-{i}"),
+                session_id.to_string(),
+            ));
+            let sender = pc.add_track(Arc::clone(&idle) as _).await?;
+            slots.push(ViewerSlot {
+                sender,
+                idle,
+                publisher: None,
+            });
+        }
+        Ok(ViewerSlots(slots))
+    }
+
+    // Ставит трек в свободный слот того же вида. None - трек уже в слоте или свободных слотов нет.
+    pub async fn attach(
+        &mut self,
+        publisher: &str,
+        track: Arc<TrackLocalStaticRTP>,
+    ) -> Option<Arc<RTCRtpSender>> {
+        let mut free = None;
+        for slot in self.0.iter_mut() {
+            let current = slot.sender.track().await?;
+            if current.id() == track.id() {
+                return None;
+            }
+            if free.is_none() && slot.publisher.is_none() && current.kind() == track.kind() {
+                free = Some(slot);
+            }
+        }
+
+        let slot = free?;
+        if let Err(e) = slot.sender.replace_track(Some(track)).await {
+            warn!(publisher:? = publisher, err:? = e; "Could not attach track to viewer slot");
+            return None;
+        }
+        slot.publisher = Some(publisher.to_string());
+        Some(Arc::clone(&slot.sender))
+    }
+
+    // Освобождает слоты с треками ушедшего издателя
+    pub async fn release(&mut self, publisher: &str) {
+        for slot in self.0.iter_mut() {
+            if slot.publisher.as_deref() != Some(publisher) {
+                continue;
+            }
+            if let Err(e) = slot
+                .sender
+                .replace_track(Some(Arc::clone(&slot.idle) as _))
+                .await
+            {
+                warn!(publisher:? = publisher, err:? = e; "Could not release viewer slot");
+                continue;
+            }
+            slot.publisher = None;
+        }
+    }
+}
+
+// Виды медиа секций offer, в которых клиент готов принимать
+fn offered_kinds(offer: &RTCSessionDescription) -> Result<Vec<RTPCodecType>> {
+    Ok(offer
+        .unmarshal()?
+        .media_descriptions
+        .iter()
+        .filter(|media| {
+            media.attribute("sendonly").is_none() && media.attribute("inactive").is_none()
+        })
+        .map(|media| RTPCodecType::from(media.media_name.media.as_str()))
+        .filter(|kind| *kind != RTPCodecType::Unspecified)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recvonly_sections() {
+        let sdp = [
+            "v=0",
+            "o=- 0 0 IN IP4 127.0.0.1",
+            "s=-",
+            "t=0 0",
+            "m=audio 9 UDP/TLS/RTP/SAVPF 111",
+            "a=recvonly",
+            "m=video 9 UDP/TLS/RTP/SAVPF 96",
+            "a=recvonly",
+            "m=video 9 UDP/TLS/RTP/SAVPF 96",
+            "a=sendonly",
+            "m=application 9 UDP/DTLS/SCTP webrtc-datachannel",
+            "",
+        ]
+        .join("\r\n");
+        let offer = RTCSessionDescription::offer(sdp).unwrap();
+
+        assert_eq!(
+            offered_kinds(&offer).unwrap(),
+            vec![RTPCodecType::Audio, RTPCodecType::Video]
+        );
+    }
+}
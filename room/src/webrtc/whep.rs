@@ -0,0 +1,39 @@
+use crate::extract::jwt::ObserverJwt;
+use crate::webrtc::axum::{AppError, WebrtcState};
+use crate::webrtc::session;
+use crate::webrtc::whip::{created, handle_media, has_content_type, MediaRequest, SDP};
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use http::{HeaderMap, StatusCode};
+use log::info;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+// WebRTC-HTTP Egress Protocol: просмотр комнаты без websocket сигналинга.
+// Перепереговоры WHEP не поддерживает, поэтому каждая секция offer становится слотом:
+// треки, опубликованные после подключения, занимают свободные слоты того же вида.
+// Смотреть могут только наблюдатели и администраторы, участники комнаты видят их в списке.
+pub async fn watch(
+    ObserverJwt(claims): ObserverJwt,
+    State(app_state): State<WebrtcState>,
+    Path(room_id): Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Response, AppError> {
+    if !has_content_type(&headers, SDP) {
+        return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response());
+    }
+
+    let session_id = session::new_session_id(claims.sub, session::WHEP);
+    let Ok(offer) = RTCSessionDescription::offer(body) else {
+        return Ok((StatusCode::BAD_REQUEST, "invalid sdp").into_response());
+    };
+    let request = MediaRequest::Watch(offer);
+    let Some(answer) =
+        handle_media(&app_state, session_id.clone(), room_id.clone(), request).await?
+    else {
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    };
+    info!(user:? = claims.sub, session_id:? = session_id, room:? = room_id; "WHEP viewer connected");
+
+    Ok(created(format!("/whep/{room_id}/{session_id}"), answer))
+}
@@ -0,0 +1,260 @@
+use crate::cluster::{ForwardedMedia, Route};
+use crate::extract::jwt::Jwt;
+use crate::webrtc::axum::{AppError, WebrtcState};
+use crate::webrtc::session::{self, SessionError};
+use anyhow::Result;
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use http::{header, HeaderMap, StatusCode};
+use log::info;
+use serde::{Deserialize, Serialize};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+// WebRTC-HTTP Ingestion Protocol (RFC 9725): публикация в комнату из OBS, GStreamer и т.п.
+// PATCH и DELETE сессии общие с WHEP.
+
+pub(crate) const SDP: &str = "application/sdp";
+const TRICKLE_ICE: &str = "application/trickle-ice-sdpfrag";
+
+// Запрос WHIP/WHEP к SFU. Если комната на другом узле, он передается туда.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", content = "playground")]
+pub enum MediaRequest {
+    #[serde(rename = "publish")]
+    Publish(RTCSessionDescription),
+
+    #[serde(rename = "watch")]
+    Watch(RTCSessionDescription),
+
+    #[serde(rename = "trickle")]
+    Trickle(Vec<RTCIceCandidateInit>),
+
+    #[serde(rename = "teardown")]
+    Teardown,
+}
+
+impl MediaRequest {
+    // Новая сессия может создать комнату, остальные запросы идут только в существующую
+    fn creates_room(&self) -> bool {
+        matches!(self, MediaRequest::Publish(_) | MediaRequest::Watch(_))
+    }
+}
+
+// Возвращает answer, если запрос был offer. Запросы к комнате на другом узле проксируются туда.
+pub(crate) async fn handle_media(
+    app_state: &WebrtcState,
+    session_id: String,
+    room_id: String,
+    request: MediaRequest,
+) -> Result<Option<RTCSessionDescription>> {
+    let route = if request.creates_room() {
+        app_state.cluster.claim(&room_id).await?
+    } else {
+        app_state.cluster.locate(&room_id).await?
+    };
+
+    if let Route::Remote(node) = route {
+        let request = ForwardedMedia {
+            session_id,
+            room_id,
+            request,
+        };
+        return app_state.cluster.forward_media(&node, &request).await;
+    }
+
+    handle_local_media(app_state, session_id, room_id, request).await
+}
+
+pub(crate) async fn handle_local_media(
+    app_state: &WebrtcState,
+    session_id: String,
+    room_id: String,
+    request: MediaRequest,
+) -> Result<Option<RTCSessionDescription>> {
+    match request {
+        MediaRequest::Publish(offer) => {
+            let answer = app_state
+                .sfu
+                .accept_ingest_offer(session_id, offer, room_id)
+                .await?;
+            return Ok(Some(answer));
+        }
+        MediaRequest::Watch(offer) => {
+            let answer = app_state
+                .sfu
+                .accept_viewer_offer(session_id, offer, room_id)
+                .await?;
+            return Ok(Some(answer));
+        }
+        MediaRequest::Trickle(candidates) => {
+            let Some(peer) = app_state.sfu.find_peer(&session_id, &room_id).await else {
+                return Err(SessionError::Unknown.into());
+            };
+            for candidate in candidates {
+                peer.pc.add_ice_candidate(candidate).await?;
+            }
+        }
+        MediaRequest::Teardown => {
+            if app_state
+                .sfu
+                .find_peer(&session_id, &room_id)
+                .await
+                .is_none()
+            {
+                return Err(SessionError::Unknown.into());
+            }
+            app_state.sfu.leave(&session_id, &room_id).await?;
+        }
+    }
+
+    Ok(None)
+}
+
+// Ответ на POST WHIP/WHEP: answer и адрес созданной сессии
+pub(crate) fn created(location: String, answer: RTCSessionDescription) -> Response {
+    (
+        StatusCode::CREATED,
+        [
+            (header::CONTENT_TYPE, SDP.to_string()),
+            (header::LOCATION, location),
+        ],
+        answer.sdp,
+    )
+        .into_response()
+}
+
+pub(crate) fn has_content_type(headers: &HeaderMap, expected: &str) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case(expected))
+}
+
+// Кандидаты из SDP фрагмента trickle ICE
+fn parse_sdpfrag(fragment: &str) -> Vec<RTCIceCandidateInit> {
+    let mut candidates = vec![];
+    let mut ufrag = None;
+    let mut mid = None;
+
+    for line in fragment.lines().map(str::trim) {
+        let Some(attribute) = line.strip_prefix("a=") else {
+            continue;
+        };
+
+        if let Some(value) = attribute.strip_prefix("ice-ufrag:") {
+            ufrag = Some(value.to_string());
+        } else if let Some(value) = attribute.strip_prefix("mid:") {
+            mid = Some(value.to_string());
+        } else if attribute.starts_with("candidate:") {
+            candidates.push(RTCIceCandidateInit {
+                candidate: attribute.to_string(),
+                sdp_mid: mid.clone(),
+                sdp_mline_index: None,
+                username_fragment: ufrag.clone(),
+            });
+        }
+    }
+
+    candidates
+}
+
+pub async fn publish(
+    Jwt(claims): Jwt,
+    State(app_state): State<WebrtcState>,
+    Path(room_id): Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Response, AppError> {
+    if !has_content_type(&headers, SDP) {
+        return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response());
+    }
+
+    // Пользователь может открыть несколько потоков, у каждого своя сессия
+    let session_id = session::new_session_id(claims.sub, session::WHIP);
+    let Ok(offer) = RTCSessionDescription::offer(body) else {
+        return Ok((StatusCode::BAD_REQUEST, "invalid sdp").into_response());
+    };
+    let request = MediaRequest::Publish(offer);
+    let Some(answer) =
+        handle_media(&app_state, session_id.clone(), room_id.clone(), request).await?
+    else {
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    };
+    info!(user:? = claims.sub, session_id:? = session_id, room:? = room_id; "WHIP publisher connected");
+
+    Ok(created(format!("/whip/{room_id}/{session_id}"), answer))
+}
+
+// Trickle ICE от клиента. ICE restart не поддерживается, клиент должен переподключиться.
+pub async fn trickle(
+    Jwt(claims): Jwt,
+    State(app_state): State<WebrtcState>,
+    Path((room_id, session_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Response, AppError> {
+    if session::owner(&session_id) != Some(claims.sub) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    if !has_content_type(&headers, TRICKLE_ICE) {
+        return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response());
+    }
+
+    let request = MediaRequest::Trickle(parse_sdpfrag(&body));
+    handle_media(&app_state, session_id, room_id, request).await?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+pub async fn teardown(
+    Jwt(claims): Jwt,
+    State(app_state): State<WebrtcState>,
+    Path((room_id, session_id)): Path<(String, String)>,
+) -> Result<Response, AppError> {
+    if session::owner(&session_id) != Some(claims.sub) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    handle_media(&app_state, session_id, room_id, MediaRequest::Teardown).await?;
+
+    Ok(StatusCode::OK.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_trickle_fragment() {
+        let fragment = "a=ice-ufrag:EsAw\r\n\
+            a=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r\n\
+            m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+            a=mid:0\r\n\
+            a=candidate:1387637174 1 udp 2122260223 192.0.2.1 61764 typ host generation 0\r\n\
+            a=end-of-candidates\r\n";
+
+        let candidates = parse_sdpfrag(fragment);
+        assert_eq!(candidates.len(), 1);
+        assert!(candidates[0].candidate.starts_with("candidate:1387637174"));
+        assert_eq!(candidates[0].sdp_mid.as_deref(), Some("0"));
+        assert_eq!(candidates[0].username_fragment.as_deref(), Some("EsAw"));
+    }
+
+    #[test]
+    fn forwarded_media_request() {
+        let request = MediaRequest::Trickle(parse_sdpfrag(
+            "a=mid:0\r\na=candidate:1 1 udp 2122260223 192.0.2.1 61764 typ host\r\n",
+        ));
+        let json = serde_json::to_string(&request).unwrap();
+
+        let MediaRequest::Trickle(candidates) = serde_json::from_str(&json).unwrap() else {
+            panic!("expected trickle request");
+        };
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].sdp_mid.as_deref(), Some("0"));
+        assert!(!request.creates_room());
+        assert!(serde_json::from_str::<MediaRequest>(r#"{"type":"teardown"}"#).is_ok());
+    }
+}